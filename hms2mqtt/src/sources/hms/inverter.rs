@@ -1,5 +1,6 @@
 use crate::protos::hoymiles::RealData::{HMSStateResponse, RealDataResDTO};
 use crate::sources::inverter::{Inverter, InverterRequest, NetworkState};
+use crate::sources::metric::{Metric, MetricLevel};
 use crc16::{State, MODBUS};
 use log::{debug, error, info, warn};
 use protobuf::Message;
@@ -41,57 +42,101 @@ impl<'a> Inverter for HMSInverter<'a> {
 }
 
 impl HMSStateResponse {
-    pub fn get_topics(
-        &self,
-        prefix: Option<&str>,
-        aliases: &HashMap<String, String>,
-    ) -> HashMap<String, f32> {
-        let mut topic_payload_pairs: HashMap<String, f32> = HashMap::new();
+    /// Returns all values of the response scaled to their units
+    pub fn get_metrics(&self) -> Vec<Metric> {
+        let mut metrics = vec![
+            Metric::new(
+                MetricLevel::Dtu,
+                "inverter_local_time",
+                self.time as f64,
+                "s",
+            ),
+            Metric::new(
+                MetricLevel::Dtu,
+                "current_power",
+                self.pv_current_power as f64 / 10.,
+                "W",
+            ),
+            Metric::new(
+                MetricLevel::Dtu,
+                "daily_yield",
+                self.pv_daily_yield as f64,
+                "Wh",
+            ),
+        ];
 
-        let pv_current_power = self.pv_current_power as f32 / 10.;
-        let pv_daily_yield = self.pv_daily_yield;
+        for inverter_state in &self.inverter_state {
+            let level = MetricLevel::Inverter(inverter_state.inv_id);
+            metrics.extend([
+                Metric::new(
+                    level,
+                    "grid_voltage",
+                    inverter_state.grid_voltage as f64 / 10.,
+                    "V",
+                ),
+                Metric::new(
+                    level,
+                    "grid_freq",
+                    inverter_state.grid_freq as f64 / 100.,
+                    "Hz",
+                ),
+                Metric::new(
+                    level,
+                    "temperature",
+                    inverter_state.temperature as f64 / 10.,
+                    "°C",
+                ),
+            ]);
+        }
 
-        let serial = aliases.get(&self.dtu_sn).unwrap_or(&self.dtu_sn);
+        for port_state in &self.port_state {
+            let level = MetricLevel::Port(port_state.pv_port);
+            metrics.extend([
+                Metric::new(level, "voltage", port_state.pv_vol as f64 / 10., "V"),
+                Metric::new(level, "curr", port_state.pv_cur as f64 / 100., "A"),
+                Metric::new(level, "power", port_state.pv_power as f64 / 10., "W"),
+                Metric::new(level, "energy", port_state.pv_energy_total as f64, "Wh"),
+                Metric::new(level, "daily_yield", port_state.pv_daily_yield as f64, "Wh"),
+            ]);
+        }
+        metrics
+    }
+
+    /// Returns the alias of the DTU or its serial if there is none
+    pub fn get_alias<'a>(&'a self, aliases: &'a HashMap<String, String>) -> &'a str {
+        aliases.get(&self.dtu_sn).unwrap_or(&self.dtu_sn)
+    }
 
-        let base_topic = if let Some(prefix) = prefix {
+    /// Returns the topic of the DTU, e.g. `prefix/dtu/<serial or alias>`
+    pub fn get_base_topic(
+        &self,
+        prefix: Option<&str>,
+        aliases: &HashMap<String, String>,
+    ) -> String {
+        let serial = self.get_alias(aliases);
+        if let Some(prefix) = prefix {
             format!("{}/dtu/{}", prefix, serial)
         } else {
             format!("dtu/{}", serial)
-        };
-
-        topic_payload_pairs.insert(
-            format!("{}/inverter_local_time", base_topic),
-            self.time as f32,
-        );
-
-        topic_payload_pairs.insert(format!("{}/current_power", base_topic), pv_current_power);
-        topic_payload_pairs.insert(format!("{}/daily_yield", base_topic), pv_daily_yield as f32);
-
-        for inverter_state in &self.inverter_state {
-            let pv_grid_voltage = inverter_state.grid_voltage as f32 / 10.;
-            let pv_grid_freq = inverter_state.grid_freq as f32 / 100.;
-            let pv_inv_temperature = inverter_state.temperature as f32 / 10.;
-            let base_topic = format!("{}/inverter/{}", base_topic, inverter_state.inv_id);
-
-            topic_payload_pairs.insert(format!("{base_topic}/grid_voltage"), pv_grid_voltage);
-            topic_payload_pairs.insert(format!("{base_topic}/grid_freq"), pv_grid_freq);
-            topic_payload_pairs.insert(format!("{base_topic}/temperature"), pv_inv_temperature);
         }
+    }
 
-        for port_state in self.port_state.iter() {
-            let pv_port_voltage = port_state.pv_vol as f32 / 10.;
-            let pv_port_curr = port_state.pv_cur as f32 / 100.;
-            let pv_port_power = port_state.pv_power as f32 / 10.;
-            let pv_port_energy = port_state.pv_energy_total as f32;
-            let pv_port_daily_yield = port_state.pv_daily_yield as f32;
-            let base_topic = format!("{}/port/{}", base_topic, port_state.pv_port);
-            topic_payload_pairs.insert(format!("{base_topic}/voltage"), pv_port_voltage);
-            topic_payload_pairs.insert(format!("{base_topic}/curr"), pv_port_curr);
-            topic_payload_pairs.insert(format!("{base_topic}/power"), pv_port_power);
-            topic_payload_pairs.insert(format!("{base_topic}/energy"), pv_port_energy);
-            topic_payload_pairs.insert(format!("{base_topic}/daily_yield"), pv_port_daily_yield);
-        }
-        topic_payload_pairs
+    pub fn get_topics(
+        &self,
+        prefix: Option<&str>,
+        aliases: &HashMap<String, String>,
+    ) -> HashMap<String, f32> {
+        let base_topic = self.get_base_topic(prefix, aliases);
+
+        self.get_metrics()
+            .into_iter()
+            .map(|metric| {
+                (
+                    format!("{}/{}", base_topic, metric.path()),
+                    metric.value as f32,
+                )
+            })
+            .collect()
     }
 }
 
@@ -0,0 +1,48 @@
+/// Part of the DTU a metric belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MetricLevel {
+    Dtu,
+    /// Inverter with its id
+    Inverter(i64),
+    /// PV port with its number
+    Port(i32),
+}
+
+/// A single value reported by an inverter
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metric {
+    pub level: MetricLevel,
+    pub name: &'static str,
+    pub value: f64,
+    pub unit: Option<&'static str>,
+}
+
+impl Metric {
+    pub fn new(level: MetricLevel, name: &'static str, value: f64, unit: &'static str) -> Self {
+        Self {
+            level,
+            name,
+            value,
+            unit: (!unit.is_empty()).then_some(unit),
+        }
+    }
+
+    /// Returns the path of the metric below the DTU, e.g. `port/1/power`
+    pub fn path(&self) -> String {
+        match self.level {
+            MetricLevel::Dtu => self.name.to_string(),
+            MetricLevel::Inverter(id) => format!("inverter/{id}/{}", self.name),
+            MetricLevel::Port(port) => format!("port/{port}/{}", self.name),
+        }
+    }
+
+    /// Returns whether the value is a momentary reading rather than an accumulated counter
+    pub fn is_instantaneous(&self) -> bool {
+        Self::is_instantaneous_unit(self.unit)
+    }
+
+    /// Returns whether values with this unit are momentary readings, i.e. no energy counters
+    pub fn is_instantaneous_unit(unit: Option<&str>) -> bool {
+        !matches!(unit, Some("Wh" | "kWh"))
+    }
+}
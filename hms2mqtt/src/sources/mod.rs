@@ -1,3 +1,4 @@
 pub mod fake;
 pub mod hms;
 pub mod inverter;
+pub mod metric;
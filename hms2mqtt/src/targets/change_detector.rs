@@ -0,0 +1,171 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::targets::{metric_filter::Pattern, mqtt::mqtt_wrapper::ConnectionState};
+
+fn default_heartbeat() -> u64 {
+    600
+}
+
+/// Suppresses changes of matching metrics within the given bands
+#[derive(Debug, Deserialize, Clone)]
+pub struct Deadband {
+    /// Keys of the metrics this deadband applies to
+    pub metrics: Pattern,
+    /// Minimal absolute change, e.g. 5 for 5 W
+    pub absolute: Option<f64>,
+    /// Minimal change relative to the last published value, e.g. 0.02 for 2%
+    pub relative: Option<f64>,
+}
+
+impl Deadband {
+    fn is_exceeded(&self, last: f64, value: f64) -> bool {
+        let change = (value - last).abs();
+        self.absolute.is_none_or(|absolute| change > absolute)
+            && self
+                .relative
+                .is_none_or(|relative| change > relative * last.abs())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChangeDetectionConfig {
+    /// Seconds after which values are published even if they did not change
+    #[serde(default = "default_heartbeat")]
+    pub heartbeat: u64,
+    /// The first matching deadband applies, other metrics are published on any change
+    #[serde(default)]
+    pub deadbands: Vec<Deadband>,
+}
+
+/// `ChangeDetector` decides whether values need to be published again.
+///
+/// Without config every value is published.
+pub struct ChangeDetector {
+    config: Option<ChangeDetectionConfig>,
+    /// Last published value and time per DTU serial and metric key
+    last_published: HashMap<(String, String), (f64, Instant)>,
+    /// Connection the last values were published with
+    connection: ConnectionState,
+}
+
+impl ChangeDetector {
+    pub fn new(config: Option<ChangeDetectionConfig>) -> Self {
+        Self {
+            config,
+            last_published: HashMap::new(),
+            connection: ConnectionState::Disconnected,
+        }
+    }
+
+    /// Returns whether any of the values changed beyond its deadband or was not published
+    /// within the heartbeat interval. If so, all values are remembered as published.
+    pub fn is_changed<K: AsRef<str>>(
+        &mut self,
+        dtu_sn: &str,
+        values: impl IntoIterator<Item = (K, f64)>,
+    ) -> bool {
+        self.is_changed_at(dtu_sn, values, Instant::now())
+    }
+
+    /// Forgets all published values, e.g. after a reconnect to publish everything again
+    pub fn reset(&mut self) {
+        self.last_published.clear();
+    }
+
+    /// Forgets all published values if the connection changed since the last call. Retained
+    /// messages, e.g. Home Assistant discovery configs, may have been lost with the previous
+    /// connection.
+    pub fn reset_on_reconnect(&mut self, connection: ConnectionState) {
+        if connection != self.connection {
+            self.connection = connection;
+            self.reset();
+        }
+    }
+
+    fn is_changed_at<K: AsRef<str>>(
+        &mut self,
+        dtu_sn: &str,
+        values: impl IntoIterator<Item = (K, f64)>,
+        now: Instant,
+    ) -> bool {
+        let Some(config) = &self.config else {
+            return true;
+        };
+        let heartbeat = Duration::from_secs(config.heartbeat);
+
+        let values: Vec<_> = values.into_iter().collect();
+        let changed = values.iter().any(|(key, value)| {
+            let key = key.as_ref();
+            let Some((last, published)) = self
+                .last_published
+                .get(&(dtu_sn.to_string(), key.to_string()))
+            else {
+                return true;
+            };
+            if now.duration_since(*published) >= heartbeat {
+                return true;
+            }
+            match config
+                .deadbands
+                .iter()
+                .find(|deadband| deadband.metrics.is_match(key))
+            {
+                Some(deadband) => deadband.is_exceeded(*last, *value),
+                None => last != value,
+            }
+        });
+
+        if changed {
+            for (key, value) in values {
+                self.last_published
+                    .insert((dtu_sn.to_string(), key.as_ref().to_string()), (value, now));
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{ChangeDetectionConfig, ChangeDetector};
+
+    #[test]
+    fn test_deadbands_and_heartbeat() {
+        let config: ChangeDetectionConfig = serde_yaml::from_str(
+            r#"
+            heartbeat: 60
+            deadbands:
+              - metrics: "port/*/power"
+                absolute: 5
+                relative: 0.02
+            "#,
+        )
+        .unwrap();
+        let mut detector = ChangeDetector::new(Some(config));
+        let start = Instant::now();
+        let mut is_changed = |key: &str, value, seconds| {
+            detector.is_changed_at("123", [(key, value)], start + Duration::from_secs(seconds))
+        };
+
+        assert!(is_changed("port/1/power", 500., 0));
+        // within the absolute deadband
+        assert!(!is_changed("port/1/power", 504., 1));
+        // within the relative deadband
+        assert!(!is_changed("port/1/power", 508., 2));
+        assert!(is_changed("port/1/power", 511., 3));
+        // the heartbeat publishes unchanged values
+        assert!(!is_changed("port/1/power", 511., 62));
+        assert!(is_changed("port/1/power", 511., 63));
+
+        assert!(is_changed("port/1/voltage", 30., 0));
+        assert!(!is_changed("port/1/voltage", 30., 1));
+        assert!(is_changed("port/1/voltage", 30.1, 2));
+    }
+}
@@ -0,0 +1,478 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local, NaiveDate};
+use flate2::{write::GzEncoder, Compression};
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    sources::metric::Metric,
+    targets::{
+        metric_filter::MetricFilter, metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias,
+    },
+};
+
+fn default_file_prefix() -> String {
+    "hms".into()
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    /// One row per snapshot with a column per value
+    #[default]
+    Csv,
+    /// One JSON object per snapshot
+    Jsonl,
+}
+
+impl FileFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            FileFormat::Csv => "csv",
+            FileFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FileLoggerConfig {
+    /// Directory the files are written to, created if missing
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub format: FileFormat,
+    /// Files are named `<prefix>_<serial or alias>_<date>.<csv|jsonl>`
+    #[serde(default = "default_file_prefix")]
+    pub file_prefix: String,
+    /// Compress the file of a day with gzip once the next day starts, files of earlier days
+    /// left by a previous run are compressed on startup
+    #[serde(default)]
+    pub compress: bool,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the logged values
+    #[serde(default)]
+    pub filter: MetricFilter,
+}
+
+/// File of the current day of a DTU
+struct DailyFile {
+    path: PathBuf,
+    date: NaiveDate,
+    /// CSV columns in the order of the header, without the time column
+    columns: Vec<String>,
+}
+
+/// `FileLogger` appends every snapshot to one file per DTU and day.
+///
+/// Days follow the local time of the inverter. CSV files get a column for every value seen on
+/// that day, the header is extended when new inverters or ports show up.
+pub struct FileLogger {
+    config: FileLoggerConfig,
+    files: HashMap<String, DailyFile>,
+}
+
+impl FileLogger {
+    pub fn new(config: &FileLoggerConfig) -> anyhow::Result<Self> {
+        // DTUs without alias use their serial, which may collide with an alias as well
+        let mut names: HashMap<String, &str> = HashMap::new();
+        for (serial, alias) in &config.serial_aliases {
+            for name in [sanitize(alias), sanitize(serial)] {
+                match names.insert(name.clone(), serial) {
+                    Some(other) if other != serial => {
+                        bail!("DTUs {other} and {serial} would log to the same files named {name}")
+                    }
+                    _ => {}
+                }
+            }
+        }
+        fs::create_dir_all(&config.directory)
+            .with_context(|| format!("could not create directory {:?}", config.directory))?;
+        Ok(Self {
+            config: config.clone(),
+            files: HashMap::new(),
+        })
+    }
+
+    fn write(&mut self, hms_state: &HMSStateResponse) -> anyhow::Result<()> {
+        let alias = hms_state.get_alias(&self.config.serial_aliases);
+        let metrics: Vec<_> = hms_state
+            .get_metrics()
+            .into_iter()
+            .filter(|metric| {
+                self.config
+                    .filter
+                    .is_included(&hms_state.dtu_sn, alias, &metric.path())
+            })
+            .collect();
+
+        // fake inverters and some firmwares report no time
+        let time = match hms_state.time {
+            time if time > 0 => UNIX_EPOCH + Duration::from_secs(time as u64),
+            _ => SystemTime::now(),
+        };
+        let time = DateTime::<Local>::from(time);
+        let date = time.date_naive();
+
+        let file = match self.files.remove(&hms_state.dtu_sn) {
+            Some(file) if file.date == date => file,
+            previous => {
+                let name = sanitize(alias);
+                match previous {
+                    Some(previous) => self.rotate(&previous.path),
+                    None => self.compress_old_files(&name, date),
+                }
+                let path = self.config.directory.join(format!(
+                    "{}_{name}_{date}.{}",
+                    self.config.file_prefix,
+                    self.config.format.extension()
+                ));
+                let columns = match self.config.format {
+                    FileFormat::Csv => read_header(&path)?,
+                    FileFormat::Jsonl => Vec::new(),
+                };
+                debug!("Logging values of {} to {path:?}", hms_state.dtu_sn);
+                DailyFile {
+                    path,
+                    date,
+                    columns,
+                }
+            }
+        };
+        let file = self.files.entry(hms_state.dtu_sn.clone()).or_insert(file);
+
+        let time = time.to_rfc3339();
+        match self.config.format {
+            FileFormat::Csv => write_csv(file, &time, &metrics),
+            FileFormat::Jsonl => {
+                let mut object = serde_json::Map::new();
+                object.insert("time".into(), time.into());
+                object.insert("serial".into(), hms_state.dtu_sn.clone().into());
+                object.insert("alias".into(), alias.into());
+                for metric in &metrics {
+                    object.insert(metric.path(), metric.value.into());
+                }
+                append(
+                    &file.path,
+                    &format!("{}\n", serde_json::Value::from(object)),
+                )
+            }
+        }
+    }
+
+    /// Compresses the files of a DTU from days before `date`, e.g. left by a stop at midnight
+    fn compress_old_files(&self, name: &str, date: NaiveDate) {
+        if !self.config.compress {
+            return;
+        }
+        let entries = match fs::read_dir(&self.config.directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to list {:?}: {e}", self.config.directory);
+                return;
+            }
+        };
+        let prefix = format!("{}_{name}_", self.config.file_prefix);
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let Some(file_date) = file_name
+                .to_str()
+                .and_then(|file_name| file_name.strip_prefix(&prefix))
+                .and_then(|rest| {
+                    [FileFormat::Csv, FileFormat::Jsonl]
+                        .into_iter()
+                        .find_map(|format| rest.strip_suffix(&format!(".{}", format.extension())))
+                })
+                .and_then(|file_date| file_date.parse::<NaiveDate>().ok())
+            else {
+                continue;
+            };
+            if file_date < date {
+                self.rotate(&entry.path());
+            }
+        }
+    }
+
+    /// Compresses a finished file if configured
+    fn rotate(&self, path: &Path) {
+        if !self.config.compress {
+            return;
+        }
+        let mut compressed = path.as_os_str().to_owned();
+        compressed.push(".gz");
+        let result = (|| -> io::Result<()> {
+            let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+            io::copy(&mut File::open(path)?, &mut encoder)?;
+            encoder.finish()?;
+            fs::remove_file(path)
+        })();
+        match result {
+            Ok(()) => info!("Compressed {path:?}"),
+            Err(e) => warn!("Failed to compress {path:?}: {e}"),
+        }
+    }
+}
+
+/// Replaces characters that are not allowed in file names
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Returns the value columns of an existing CSV file
+fn read_header(path: &Path) -> anyhow::Result<Vec<String>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut header = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut header)?;
+    Ok(header
+        .trim_end()
+        .split(',')
+        .skip(1)
+        .map(str::to_string)
+        .collect())
+}
+
+fn append(path: &Path, contents: &str) -> anyhow::Result<()> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+fn write_csv(file: &mut DailyFile, time: &str, metrics: &[Metric]) -> anyhow::Result<()> {
+    let values: HashMap<_, _> = metrics
+        .iter()
+        .map(|metric| (metric.path(), metric.value))
+        .collect();
+    let new_columns: Vec<_> = metrics
+        .iter()
+        .map(Metric::path)
+        .filter(|path| !file.columns.contains(path))
+        .collect();
+
+    if file.columns.is_empty() || !new_columns.is_empty() {
+        let old_columns = file.columns.len();
+        file.columns.extend(new_columns);
+        let header = format!("time,{}\n", file.columns.join(","));
+        if file.path.exists() {
+            // rewrite the file with the extended header, older rows get empty values
+            let padding = ",".repeat(file.columns.len() - old_columns);
+            let mut contents = header;
+            for row in fs::read_to_string(&file.path)?.lines().skip(1) {
+                contents.push_str(row);
+                contents.push_str(&padding);
+                contents.push('\n');
+            }
+            let temporary_file = file.path.with_extension("tmp");
+            fs::write(&temporary_file, contents)?;
+            fs::rename(&temporary_file, &file.path)?;
+        } else {
+            append(&file.path, &header)?;
+        }
+    }
+
+    let mut row = time.to_string();
+    for column in &file.columns {
+        row.push(',');
+        if let Some(value) = values.get(column) {
+            row.push_str(&value.to_string());
+        }
+    }
+    row.push('\n');
+    append(&file.path, &row)
+}
+
+impl MetricPublisher for FileLogger {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        if let Err(e) = self.write(hms_state) {
+            warn!("Failed to log values of {}: {e}", hms_state.dtu_sn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{self, File},
+        io::Read,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use chrono::{DateTime, Local};
+    use flate2::read::GzDecoder;
+
+    use super::{FileLogger, FileLoggerConfig};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, PortState},
+        targets::metric_publisher::MetricPublisher,
+    };
+
+    fn snapshot(time: i32, ports: i32) -> HMSStateResponse {
+        HMSStateResponse {
+            dtu_sn: "123".into(),
+            time,
+            pv_current_power: 1234,
+            port_state: (1..=ports)
+                .map(|pv_port| PortState {
+                    pv_port,
+                    pv_power: 617,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn date(time: i32) -> String {
+        DateTime::<Local>::from(UNIX_EPOCH + Duration::from_secs(time as u64))
+            .date_naive()
+            .to_string()
+    }
+
+    #[test]
+    fn test_csv_header_and_rotation() {
+        let directory = std::env::temp_dir().join(format!("file_logger_{}", std::process::id()));
+        let config: FileLoggerConfig = serde_yaml::from_str(&format!(
+            r#"
+            directory: {directory:?}
+            compress: true
+            serial_aliases:
+              - serial: "123"
+                alias: "roof"
+            filter:
+              include: ["current_power", "port/*/power"]
+            "#
+        ))
+        .unwrap();
+        let mut logger = FileLogger::new(&config).unwrap();
+
+        // noon UTC, so both days are different in every time zone of the test machine
+        let (first_day, second_day) = (1_699_963_200, 1_700_049_600);
+        logger.publish(&snapshot(first_day, 1));
+        logger.publish(&snapshot(first_day + 30, 2));
+        let first_file = directory.join(format!("hms_roof_{}.csv", date(first_day)));
+        let contents = fs::read_to_string(&first_file).unwrap();
+        let rows: Vec<_> = contents.lines().collect();
+        assert_eq!(rows[0], "time,current_power,port/1/power,port/2/power");
+        assert!(rows[1].ends_with(",123.4,61.7,"), "{}", rows[1]);
+        assert!(rows[2].ends_with(",123.4,61.7,61.7"), "{}", rows[2]);
+
+        logger.publish(&snapshot(second_day, 1));
+        assert!(!first_file.exists());
+        let mut decompressed = String::new();
+        GzDecoder::new(File::open(first_file.with_extension("csv.gz")).unwrap())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, contents);
+        let second_file = directory.join(format!("hms_roof_{}.csv", date(second_day)));
+        assert_eq!(
+            fs::read_to_string(second_file).unwrap().lines().next(),
+            Some("time,current_power,port/1/power")
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_jsonl() {
+        let directory =
+            std::env::temp_dir().join(format!("file_logger_jsonl_{}", std::process::id()));
+        let config: FileLoggerConfig = serde_yaml::from_str(&format!(
+            r#"
+            directory: {directory:?}
+            format: jsonl
+            filter:
+              include: ["current_power"]
+            "#
+        ))
+        .unwrap();
+        let mut logger = FileLogger::new(&config).unwrap();
+        logger.publish(&snapshot(1_699_963_200, 1));
+
+        let file = directory.join(format!("hms_123_{}.jsonl", date(1_699_963_200)));
+        let line: serde_json::Value =
+            serde_json::from_str(fs::read_to_string(file).unwrap().trim_end()).unwrap();
+        assert_eq!(line["serial"], "123");
+        assert_eq!(line["current_power"], 123.4);
+        assert!(line["time"]
+            .as_str()
+            .unwrap()
+            .starts_with(&date(1_699_963_200)));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_compress_files_of_previous_runs() {
+        let directory =
+            std::env::temp_dir().join(format!("file_logger_startup_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        for name in [
+            "hms_123_2023-01-01.csv",
+            "hms_123_2023-01-02.jsonl",
+            "hms_1234_2023-01-01.csv",
+            "hms_123_notes.csv",
+        ] {
+            fs::write(directory.join(name), "time\n").unwrap();
+        }
+        let config: FileLoggerConfig = serde_yaml::from_str(&format!(
+            "directory: {directory:?}\ncompress: true\nfilter: {{include: [current_power]}}"
+        ))
+        .unwrap();
+        let mut logger = FileLogger::new(&config).unwrap();
+        logger.publish(&snapshot(1_699_963_200, 1));
+
+        assert!(directory.join("hms_123_2023-01-01.csv.gz").exists());
+        assert!(directory.join("hms_123_2023-01-02.jsonl.gz").exists());
+        assert!(!directory.join("hms_123_2023-01-01.csv").exists());
+        // other DTUs and unrelated files are left alone
+        assert!(directory.join("hms_1234_2023-01-01.csv").exists());
+        assert!(directory.join("hms_123_notes.csv").exists());
+        assert!(directory
+            .join(format!("hms_123_{}.csv", date(1_699_963_200)))
+            .exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_aliases() {
+        let config = |aliases: &str| {
+            serde_yaml::from_str::<FileLoggerConfig>(&format!(
+                "directory: {:?}\nserial_aliases: {aliases}",
+                std::env::temp_dir()
+            ))
+            .unwrap()
+        };
+        assert!(FileLogger::new(&config(
+            "[{serial: '1', alias: 'roof/east'}, {serial: '2', alias: 'roof:east'}]"
+        ))
+        .is_err());
+        assert!(FileLogger::new(&config(
+            "[{serial: '1', alias: '2'}, {serial: '2', alias: 'garage'}]"
+        ))
+        .is_err());
+        assert!(FileLogger::new(&config(
+            "[{serial: '1', alias: 'roof'}, {serial: '2', alias: 'garage'}]"
+        ))
+        .is_ok());
+    }
+}
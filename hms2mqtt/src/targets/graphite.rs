@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    targets::{
+        metric_filter::MetricFilter, metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias,
+    },
+};
+
+fn default_port() -> u16 {
+    2003
+}
+
+fn default_prefix() -> String {
+    "hms".into()
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GraphiteConfig {
+    /// Host of the carbon plaintext receiver
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// First element of every path, e.g. `hms.dtu.<serial or alias>.port.1.power`
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the written values
+    #[serde(default)]
+    pub filter: MetricFilter,
+    /// Seconds before connecting or writing is aborted
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// Replaces characters with a special meaning in Graphite and StatsD paths
+fn sanitize(element: &str) -> String {
+    element
+        .chars()
+        .map(|c| match c {
+            '.' | ' ' | ':' | '|' | '@' | '#' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+impl HMSStateResponse {
+    /// Returns the included values with the topic hierarchy as dotted path,
+    /// e.g. `prefix.dtu.<serial or alias>.port.1.power`
+    pub(crate) fn get_dotted_metrics(
+        &self,
+        prefix: &str,
+        aliases: &HashMap<String, String>,
+        filter: &MetricFilter,
+    ) -> Vec<(String, f64)> {
+        let alias = self.get_alias(aliases);
+        let prefix = (!prefix.is_empty()).then_some(prefix);
+        let base_topic = self.get_base_topic(prefix, aliases);
+        self.get_metrics()
+            .into_iter()
+            .filter(|metric| filter.is_included(&self.dtu_sn, alias, &metric.path()))
+            .map(|metric| {
+                let topic = format!("{base_topic}/{}", metric.path());
+                let path: Vec<_> = topic.split('/').map(sanitize).collect();
+                (path.join("."), metric.value)
+            })
+            .collect()
+    }
+}
+
+/// `Graphite` writes every value using the plaintext protocol of carbon.
+pub struct Graphite {
+    config: GraphiteConfig,
+    stream: Option<TcpStream>,
+}
+
+impl Graphite {
+    pub fn new(config: &GraphiteConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            config: config.clone(),
+            stream: None,
+        })
+    }
+
+    fn connect(&self) -> anyhow::Result<TcpStream> {
+        let address = format!("{}:{}", self.config.host, self.config.port);
+        let timeout = Duration::from_secs(self.config.timeout);
+        let socket_address = address
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("could not resolve {address}"))?;
+        let stream = TcpStream::connect_timeout(&socket_address, timeout)
+            .with_context(|| format!("could not connect to {address}"))?;
+        stream.set_write_timeout(Some(timeout))?;
+        info!("Connected to Graphite at {address}");
+        Ok(stream)
+    }
+
+    fn write(&mut self, lines: &str) -> anyhow::Result<()> {
+        // writes to a connection closed by the server only fail after the lines are lost
+        let mut stream = match self.stream.take().filter(is_open) {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+        if let Err(e) = stream.write_all(lines.as_bytes()) {
+            // carbon closes idle connections, so reconnect once
+            debug!("Reconnecting to Graphite after {e}");
+            stream = self.connect()?;
+            stream.write_all(lines.as_bytes())?;
+        }
+        self.stream = Some(stream);
+        Ok(())
+    }
+}
+
+/// Returns whether the server did not close the connection, it never sends anything otherwise
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = match stream.peek(&mut [0]) {
+        Err(e) => e.kind() == ErrorKind::WouldBlock,
+        Ok(length) => length > 0,
+    };
+    open && stream.set_nonblocking(false).is_ok()
+}
+
+impl MetricPublisher for Graphite {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        // fake inverters and some firmwares report no time
+        let timestamp = match hms_state.time {
+            time if time > 0 => time as u64,
+            _ => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        let lines: String = hms_state
+            .get_dotted_metrics(
+                &self.config.prefix,
+                &self.config.serial_aliases,
+                &self.config.filter,
+            )
+            .into_iter()
+            .map(|(path, value)| format!("{path} {value} {timestamp}\n"))
+            .collect();
+        if let Err(e) = self.write(&lines) {
+            warn!("Failed to write to Graphite: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+    };
+
+    use super::{Graphite, GraphiteConfig};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, PortState},
+        targets::metric_publisher::MetricPublisher,
+    };
+
+    #[test]
+    fn test_plaintext_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config: GraphiteConfig = serde_yaml::from_str(&format!(
+            r#"
+            host: "127.0.0.1"
+            port: {}
+            prefix: "solar"
+            serial_aliases:
+              - serial: "123"
+                alias: "my.roof"
+            filter:
+              include: ["current_power", "port/*/power"]
+            "#,
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        let mut graphite = Graphite::new(&config).unwrap();
+        graphite.publish(&HMSStateResponse {
+            dtu_sn: "123".into(),
+            time: 1_700_000_000,
+            pv_current_power: 1234,
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_power: 617,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        drop(graphite);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(
+            received,
+            "solar.dtu.my_roof.current_power 123.4 1700000000\n\
+             solar.dtu.my_roof.port.1.power 61.7 1700000000\n"
+        );
+    }
+
+    #[test]
+    fn test_reconnect_after_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config: GraphiteConfig = serde_yaml::from_str(&format!(
+            "host: '127.0.0.1'\nport: {}\nfilter: {{include: [current_power]}}",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        let snapshot = |time| HMSStateResponse {
+            dtu_sn: "123".into(),
+            time,
+            ..Default::default()
+        };
+        let mut graphite = Graphite::new(&config).unwrap();
+        graphite.publish(&snapshot(1_700_000_000));
+        // carbon closes idle connections
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        drop(stream);
+
+        graphite.publish(&snapshot(1_700_000_060));
+        drop(graphite);
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "hms.dtu.123.current_power 0 1700000060\n");
+    }
+}
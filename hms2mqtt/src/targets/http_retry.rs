@@ -0,0 +1,93 @@
+//! Retries of HTTP requests shared by the targets sending snapshots over HTTP.
+
+use std::{error::Error, thread, time::Duration};
+
+use log::warn;
+
+pub(crate) enum SendError {
+    /// The server is unreachable or overloaded, the request may succeed later
+    Retryable(String),
+    /// The server refused the request, sending it again won't help
+    Rejected(String),
+}
+
+impl From<ureq::Error> for SendError {
+    fn from(error: ureq::Error) -> Self {
+        match error {
+            ureq::Error::Status(status, response) => {
+                let message = format!(
+                    "status {status}: {}",
+                    response.into_string().unwrap_or_default()
+                );
+                if status == 429 || status >= 500 {
+                    SendError::Retryable(message)
+                } else {
+                    SendError::Rejected(message)
+                }
+            }
+            ureq::Error::Transport(transport) => {
+                // the URL is left out, its query may contain credentials
+                let mut message = transport.kind().to_string();
+                if let Some(details) = transport.message() {
+                    message.push_str(&format!(": {details}"));
+                }
+                if let Some(source) = transport.source() {
+                    message.push_str(&format!(": {source}"));
+                }
+                SendError::Retryable(message)
+            }
+        }
+    }
+}
+
+/// Calls `send` until it succeeds, is rejected or `max_retries` retries failed.
+///
+/// The first retry waits `retry_interval` milliseconds, every further retry twice as long.
+pub(crate) fn send_with_retries(
+    target: &str,
+    max_retries: u32,
+    retry_interval: u64,
+    mut send: impl FnMut() -> Result<(), SendError>,
+) -> Result<(), SendError> {
+    let mut attempt = 0;
+    loop {
+        match send() {
+            Err(SendError::Retryable(message)) => {
+                warn!("Failed to send to {target}: {message}");
+                if attempt >= max_retries {
+                    return Err(SendError::Retryable(message));
+                }
+                thread::sleep(Duration::from_millis(
+                    retry_interval.saturating_mul(1 << attempt.min(16)),
+                ));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use super::SendError;
+
+    #[test]
+    fn test_transport_errors_hide_the_url() {
+        // a port nobody listens on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let error = ureq::get(&format!("http://127.0.0.1:{port}/write?p=secret"))
+            .call()
+            .unwrap_err();
+        let SendError::Retryable(message) = error.into() else {
+            panic!("transport errors are retryable");
+        };
+        assert!(message.contains("Connection Failed"), "{message}");
+        assert!(!message.contains("secret"), "{message}");
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    sources::metric::{Metric, MetricLevel},
+    targets::{
+        http_retry::{send_with_retries, SendError},
+        metric_filter::MetricFilter,
+        metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias,
+    },
+};
+
+fn default_measurement_prefix() -> String {
+    "hms".into()
+}
+
+fn default_batch_size() -> usize {
+    1
+}
+
+fn default_max_retries() -> u32 {
+    // failed lines are kept for the next write anyway
+    0
+}
+
+fn default_retry_interval() -> u64 {
+    1000
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+fn default_max_pending_lines() -> usize {
+    100_000
+}
+
+/// HTTP write API of the server
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InfluxDbVersion {
+    /// `/write` with database and retention policy
+    V1,
+    /// `/api/v2/write` with organization, bucket and token
+    #[default]
+    V2,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct InfluxDbConfig {
+    /// Base URL of the server, e.g. `http://localhost:8086`
+    pub url: String,
+    #[serde(default)]
+    pub api_version: InfluxDbVersion,
+    /// Database of the v1 API
+    pub database: Option<String>,
+    /// Retention policy of the v1 API, the default policy of the database if not set
+    pub retention_policy: Option<String>,
+    /// User of the v1 API
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Organization of the v2 API
+    pub org: Option<String>,
+    /// Bucket of the v2 API
+    pub bucket: Option<String>,
+    /// API token of the v2 API
+    pub token: Option<String>,
+    /// Points are written to the measurements `<prefix>_dtu`, `<prefix>_inverter` and `<prefix>_port`
+    #[serde(default = "default_measurement_prefix")]
+    pub measurement_prefix: String,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the written values
+    #[serde(default)]
+    pub filter: MetricFilter,
+    /// Number of snapshots written in one request
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Retries of a failed write before the points are kept for the next one. Writes block the
+    /// polling of the inverters, so an unreachable server delays it by up to
+    /// `(max_retries + 1) * timeout` plus the retry delays.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Milliseconds before the first retry, doubled for every further retry
+    #[serde(default = "default_retry_interval")]
+    pub retry_interval: u64,
+    /// Seconds before a request is aborted
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Lines kept while the server is unreachable, the oldest ones are dropped
+    #[serde(default = "default_max_pending_lines")]
+    pub max_pending_lines: usize,
+}
+
+/// `InfluxDb` writes every snapshot as line protocol using the inverter time as point time.
+pub struct InfluxDb {
+    config: InfluxDbConfig,
+    agent: ureq::Agent,
+    /// Lines not written yet, oldest first
+    pending_lines: VecDeque<String>,
+    pending_snapshots: usize,
+}
+
+impl InfluxDb {
+    pub fn new(config: &InfluxDbConfig) -> anyhow::Result<Self> {
+        match config.api_version {
+            InfluxDbVersion::V1 if config.database.is_none() => {
+                bail!("the InfluxDB v1 API requires a database")
+            }
+            InfluxDbVersion::V2 if config.org.is_none() || config.bucket.is_none() => {
+                bail!("the InfluxDB v2 API requires an org and a bucket")
+            }
+            _ => {}
+        }
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout))
+            .build();
+        Ok(Self {
+            config: config.clone(),
+            agent,
+            pending_lines: VecDeque::new(),
+            pending_snapshots: 0,
+        })
+    }
+
+    fn get_lines(&self, hms_state: &HMSStateResponse) -> Vec<String> {
+        let alias = hms_state.get_alias(&self.config.serial_aliases);
+        let mut components: BTreeMap<MetricLevel, Vec<Metric>> = BTreeMap::new();
+        for metric in hms_state.get_metrics() {
+            if self
+                .config
+                .filter
+                .is_included(&hms_state.dtu_sn, alias, &metric.path())
+            {
+                components.entry(metric.level).or_default().push(metric);
+            }
+        }
+
+        // fake inverters and some firmwares report no time
+        let timestamp = match hms_state.time {
+            time if time > 0 => time as u64,
+            _ => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+
+        components
+            .into_iter()
+            .map(|(level, metrics)| {
+                let (component, id_tag) = match level {
+                    MetricLevel::Dtu => ("dtu", None),
+                    MetricLevel::Inverter(id) => ("inverter", Some(("inverter", id.to_string()))),
+                    MetricLevel::Port(port) => ("port", Some(("port", port.to_string()))),
+                };
+                let mut line = escape(
+                    &format!("{}_{component}", self.config.measurement_prefix),
+                    &[',', ' '],
+                );
+                for (key, value) in [
+                    ("serial", hms_state.dtu_sn.clone()),
+                    ("alias", alias.into()),
+                ]
+                .into_iter()
+                .chain(id_tag)
+                {
+                    line.push_str(&format!(",{key}={}", escape(&value, &[',', '=', ' '])));
+                }
+                let fields: Vec<_> = metrics
+                    .iter()
+                    .map(|metric| {
+                        format!("{}={}", escape(metric.name, &[',', '=', ' ']), metric.value)
+                    })
+                    .collect();
+                format!("{line} {} {timestamp}", fields.join(","))
+            })
+            .collect()
+    }
+
+    fn write(&self, body: &str) -> Result<(), SendError> {
+        let config = &self.config;
+        let url = match config.api_version {
+            InfluxDbVersion::V1 => format!("{}/write", config.url.trim_end_matches('/')),
+            InfluxDbVersion::V2 => format!("{}/api/v2/write", config.url.trim_end_matches('/')),
+        };
+        let mut request = self
+            .agent
+            .post(&url)
+            .query("precision", "s")
+            .set("Content-Type", "text/plain; charset=utf-8");
+        request = match config.api_version {
+            InfluxDbVersion::V1 => {
+                let mut request =
+                    request.query("db", config.database.as_deref().unwrap_or_default());
+                if let Some(retention_policy) = &config.retention_policy {
+                    request = request.query("rp", retention_policy);
+                }
+                // credentials in the query would end up in logs and proxies
+                if let Some(username) = &config.username {
+                    let credentials = format!(
+                        "{username}:{}",
+                        config.password.as_deref().unwrap_or_default()
+                    );
+                    request = request.set(
+                        "Authorization",
+                        &format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+                    );
+                }
+                request
+            }
+            InfluxDbVersion::V2 => {
+                let mut request = request
+                    .query("org", config.org.as_deref().unwrap_or_default())
+                    .query("bucket", config.bucket.as_deref().unwrap_or_default());
+                if let Some(token) = &config.token {
+                    request = request.set("Authorization", &format!("Token {token}"));
+                }
+                request
+            }
+        };
+
+        request.send_string(body)?;
+        Ok(())
+    }
+
+    /// Writes all pending lines, retrying with an increasing delay
+    fn flush(&mut self) {
+        // failed lines are retried together with the next batch
+        self.pending_snapshots = 0;
+        let body = Vec::from(self.pending_lines.clone()).join("\n");
+        let result = send_with_retries(
+            "InfluxDB",
+            self.config.max_retries,
+            self.config.retry_interval,
+            || self.write(&body),
+        );
+        match result {
+            Ok(()) => {
+                debug!("Wrote {} lines to InfluxDB", self.pending_lines.len());
+                self.pending_lines.clear();
+            }
+            // e.g. because of a type conflict
+            Err(SendError::Rejected(message)) => {
+                warn!(
+                    "InfluxDB rejected {} lines, dropping them: {message}",
+                    self.pending_lines.len()
+                );
+                self.pending_lines.clear();
+            }
+            Err(SendError::Retryable(_)) => info!(
+                "Keeping {} lines for the next write to InfluxDB",
+                self.pending_lines.len()
+            ),
+        }
+    }
+}
+
+/// Escapes the given characters with a backslash as required by the line protocol
+fn escape(value: &str, characters: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || characters.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl MetricPublisher for InfluxDb {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        self.pending_lines.extend(self.get_lines(hms_state));
+        let excess = self
+            .pending_lines
+            .len()
+            .saturating_sub(self.config.max_pending_lines);
+        if excess > 0 {
+            warn!("Dropping {excess} InfluxDB lines that could not be written");
+            self.pending_lines.drain(..excess);
+        }
+
+        self.pending_snapshots += 1;
+        if self.pending_snapshots >= self.config.batch_size {
+            self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InfluxDb, InfluxDbConfig};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, InverterState, PortState},
+        targets::{http_stub::HttpStub, metric_publisher::MetricPublisher},
+    };
+
+    fn snapshot(time: i32) -> HMSStateResponse {
+        HMSStateResponse {
+            dtu_sn: "123".into(),
+            time,
+            pv_current_power: 1234,
+            inverter_state: vec![InverterState {
+                inv_id: 42,
+                temperature: 215,
+                ..Default::default()
+            }],
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_power: 617,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn config(stub: &HttpStub, extra: &str) -> InfluxDbConfig {
+        serde_yaml::from_str(&format!(
+            r#"
+            url: "{}"
+            org: "home"
+            bucket: "pv"
+            token: "secret"
+            retry_interval: 1
+            serial_aliases:
+              - serial: "123"
+                alias: "my roof"
+            {extra}
+            "#,
+            stub.url()
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_v2_write_with_retry() {
+        let stub = HttpStub::start(vec![503, 204]);
+        let mut influxdb = InfluxDb::new(&config(
+            &stub,
+            "max_retries: 1\n            filter: {include: ['**power', '**temperature']}",
+        ))
+        .unwrap();
+        influxdb.publish(&snapshot(1_700_000_000));
+
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 2);
+        let request = &requests[1];
+        assert_eq!(request.method, "POST");
+        assert!(request.path.starts_with("/api/v2/write?"));
+        assert!(request.path.contains("bucket=pv"));
+        assert!(request.path.contains("precision=s"));
+        assert_eq!(request.headers["authorization"], "Token secret");
+        assert_eq!(
+            request.body_text(),
+            [
+                r"hms_dtu,serial=123,alias=my\ roof current_power=123.4 1700000000",
+                r"hms_inverter,serial=123,alias=my\ roof,inverter=42 temperature=21.5 1700000000",
+                r"hms_port,serial=123,alias=my\ roof,port=1 power=61.7 1700000000",
+            ]
+            .join("\n")
+        );
+        assert!(influxdb.pending_lines.is_empty());
+    }
+
+    #[test]
+    fn test_v1_batches_and_keeps_failed_lines() {
+        let stub = HttpStub::start(vec![500, 500, 204]);
+        let mut influxdb = InfluxDb::new(&config(
+            &stub,
+            "api_version: v1\n            database: pv\n            username: user\n            password: pass\n            batch_size: 2\n            max_retries: 1\n            filter: {include: [current_power]}",
+        ))
+        .unwrap();
+
+        influxdb.publish(&snapshot(1));
+        assert!(stub.requests().is_empty());
+        influxdb.publish(&snapshot(2));
+        // both attempts failed, the lines are kept
+        assert_eq!(stub.requests().len(), 2);
+        assert_eq!(influxdb.pending_lines.len(), 2);
+
+        influxdb.publish(&snapshot(3));
+        influxdb.publish(&snapshot(4));
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].path.starts_with("/write?"));
+        assert!(requests[2].path.contains("db=pv"));
+        assert!(!requests[2].path.contains("pass"));
+        assert_eq!(requests[2].headers["authorization"], "Basic dXNlcjpwYXNz");
+        assert_eq!(requests[2].body_text().lines().count(), 4);
+        assert!(influxdb.pending_lines.is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use std::{collections::HashMap, str::FromStr};
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+/// A glob or, if prefixed with `re:`, a regular expression matching metric keys.
+///
+/// In globs `*` matches within one level of a key like `port/1/power`, `**` matches across levels
+/// and `?` matches a single character.
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        if let Some(regex) = pattern.strip_prefix("re:") {
+            return Ok(Self(Regex::new(regex)?));
+        }
+
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    regex.push_str(".*");
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        Ok(Self(Regex::new(&regex)?))
+    }
+}
+
+impl Pattern {
+    pub fn is_match(&self, key: &str) -> bool {
+        self.0.is_match(key)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+        pattern
+            .parse()
+            .map_err(|e| serde::de::Error::custom(format!("invalid pattern '{pattern}': {e}")))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct FilterRules {
+    /// Only keys matching one of these patterns are published. Empty includes everything.
+    #[serde(default)]
+    include: Vec<Pattern>,
+    /// Keys matching one of these patterns are not published
+    #[serde(default)]
+    exclude: Vec<Pattern>,
+}
+
+impl FilterRules {
+    fn matches(&self, key: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|pattern| pattern.is_match(key)))
+            && !self.exclude.iter().any(|pattern| pattern.is_match(key))
+    }
+}
+
+/// `MetricFilter` selects which metrics a target publishes.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MetricFilter {
+    #[serde(flatten)]
+    rules: FilterRules,
+    /// Rules replacing the ones above for single DTUs, keyed by serial or alias
+    #[serde(default)]
+    dtus: HashMap<String, FilterRules>,
+}
+
+impl MetricFilter {
+    pub fn is_included(&self, dtu_sn: &str, alias: &str, key: &str) -> bool {
+        self.dtus
+            .get(dtu_sn)
+            .or_else(|| self.dtus.get(alias))
+            .unwrap_or(&self.rules)
+            .matches(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MetricFilter;
+
+    #[test]
+    fn test_filter() {
+        let filter: MetricFilter = serde_yaml::from_str(
+            r#"
+            include: ["port/*/power", "**energy", "re:^current_.*"]
+            exclude: ["port/4/*"]
+            dtus:
+              roof:
+                exclude: ["inverter/**"]
+            "#,
+        )
+        .unwrap();
+
+        assert!(filter.is_included("123", "123", "port/1/power"));
+        assert!(filter.is_included("123", "123", "port/1/energy"));
+        assert!(filter.is_included("123", "123", "current_power"));
+        assert!(!filter.is_included("123", "123", "port/4/power"));
+        assert!(!filter.is_included("123", "123", "port/1/voltage"));
+        assert!(!filter.is_included("123", "123", "inverter/1/power"));
+
+        assert!(filter.is_included("456", "roof", "port/1/voltage"));
+        assert!(!filter.is_included("456", "roof", "inverter/1/temperature"));
+    }
+}
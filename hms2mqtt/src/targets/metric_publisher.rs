@@ -2,4 +2,12 @@ use crate::protos::hoymiles::RealData::HMSStateResponse;
 
 pub trait MetricPublisher {
     fn publish(&mut self, hms_state: &HMSStateResponse);
+
+    /// Called after every poll of the inverter at `host`, whether it returned a snapshot or not
+    fn poll_result(&mut self, _host: &str, _success: bool) {}
+
+    /// Whether published snapshots currently reach their destination
+    fn is_connected(&self) -> bool {
+        true
+    }
 }
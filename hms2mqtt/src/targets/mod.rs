@@ -1,2 +1,21 @@
+pub mod change_detector;
+pub mod file_logger;
+pub mod graphite;
+pub(crate) mod http_retry;
+#[cfg(test)]
+pub(crate) mod http_stub;
+pub mod influxdb;
+pub mod metric_filter;
 pub mod metric_publisher;
 pub mod mqtt;
+pub mod nats;
+pub mod offline_buffer;
+pub mod otlp;
+pub mod postgres;
+pub mod prometheus;
+pub mod pvoutput;
+pub mod redis;
+pub mod sqlite;
+pub mod statsd;
+pub mod template;
+pub mod webhook;
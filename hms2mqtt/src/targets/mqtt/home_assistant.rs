@@ -1,60 +1,194 @@
+use std::collections::HashMap;
 use std::sync::mpsc::channel;
 
-use crate::protos::hoymiles::RealData::HMSStateResponse;
+use crate::protos::hoymiles::RealData::{HMSStateResponse, InverterState, PortState};
+use crate::targets::change_detector::ChangeDetector;
 use crate::targets::metric_publisher::MetricPublisher;
 use crate::targets::mqtt::home_assistant_config::{DeviceConfig, SensorConfig};
-use crate::targets::mqtt::mqtt_wrapper::{MqttWrapper, QoS};
+use crate::targets::mqtt::mqtt_wrapper::{MqttWrapper, PublishProperties};
+use crate::targets::mqtt::retained_topics::RetainedTopics;
 
-use log::{debug, error};
+use chrono::{DateTime, Utc};
+use log::{debug, error, warn};
 use serde_json::json;
 
-use super::mqtt_config::MqttConfig;
+use super::mqtt_config::{MqttConfig, UniqueIdScheme};
 
 pub struct HomeAssistant<MQTT: MqttWrapper> {
     client: MQTT,
+    config: MqttConfig,
+    retained_topics: RetainedTopics,
+    /// Last published energy counters per DTU serial
+    energy_totals: HashMap<String, HashMap<String, f64>>,
+    change_detector: ChangeDetector,
 }
 
 impl<MQTT: MqttWrapper> HomeAssistant<MQTT> {
-    pub fn new(config: &MqttConfig) -> Self {
+    pub fn new(config: &MqttConfig) -> anyhow::Result<Self> {
         let (tx, _rx) = channel();
-        let client = MQTT::new(config, tx);
-        Self { client }
+        let client = MQTT::new(config, tx)?;
+        Ok(Self {
+            client,
+            config: config.clone(),
+            retained_topics: RetainedTopics::load_or_warn(config.state_file.as_deref()),
+            energy_totals: HashMap::new(),
+            change_detector: ChangeDetector::new(config.publish_on_change.clone()),
+        })
     }
 
-    fn publish_json(&mut self, topic: &str, payload: serde_json::Value) {
+    /// Removes every discovery config and state this target remembers publishing and closes the connection.
+    pub fn purge(mut self) -> anyhow::Result<()> {
+        self.retained_topics
+            .purge(&mut self.client, self.config.qos)
+    }
+
+    fn publish_json(
+        &mut self,
+        topic: &str,
+        payload: serde_json::Value,
+        retain: bool,
+        dtu_sn: &str,
+    ) {
         debug!("Publishing to {topic} with payload {payload}");
 
         let payload = serde_json::to_string(&payload).unwrap();
-        if let Err(e) = self.client.publish(topic, QoS::AtMostOnce, true, payload) {
+        let properties = PublishProperties {
+            content_type: Some("application/json".into()),
+            message_expiry: None,
+            user_properties: vec![("serial".into(), dtu_sn.into())],
+        };
+        if let Err(e) =
+            self.client
+                .publish_with_properties(topic, self.config.qos, retain, payload, properties)
+        {
             error!("Failed to publish message: {e:?}");
         }
     }
 
-    fn publish_configs(&mut self, config_topic: &str, sensor_configs: &Vec<SensorConfig>) {
+    fn clear_topics(&mut self, topics: Vec<String>) {
+        // an empty retained message removes the entity from home assistant and the topic from the broker
+        for topic in topics {
+            debug!("Removing obsolete topic {topic}");
+            if let Err(e) = self.client.publish(topic, self.config.qos, true, "") {
+                error!("Failed to publish message: {e:?}");
+            }
+        }
+    }
+
+    fn publish_configs(
+        &mut self,
+        config_topic: &str,
+        sensor_configs: &Vec<SensorConfig>,
+        dtu_sn: &str,
+    ) {
         // configs let home assistant know what sensors are available and where to find them
         for sensor_config in sensor_configs {
-            let config_topic = format!("{}/{}/config", config_topic, sensor_config.unique_id);
+            let config_topic = sensor_config_topic(config_topic, sensor_config);
             let config_payload = serde_json::to_value(sensor_config).unwrap();
-            self.publish_json(&config_topic, config_payload);
+            // configs are always retained, otherwise home assistant forgets the sensors on restart
+            self.publish_json(&config_topic, config_payload, true, dtu_sn);
         }
     }
 
-    fn publish_states(&mut self, hms_state: &HMSStateResponse, state_topic: &str) {
+    fn get_state_payload(&mut self, hms_state: &HMSStateResponse) -> serde_json::Value {
         // states contain the actual data
-        let json_payload = hms_state.to_json_payload();
-        self.publish_json(state_topic, json_payload);
+        let mut json_payload = hms_state.to_json_payload();
+        if let Some(values) = json_payload.as_object_mut() {
+            let alias = hms_state.get_alias(&self.config.serial_aliases);
+            values.retain(|key, _| {
+                self.config
+                    .filter
+                    .is_included(&hms_state.dtu_sn, alias, key)
+            });
+        }
+        self.guard_energy_totals(&hms_state.dtu_sn, &mut json_payload);
+        json_payload
+    }
+
+    /// Prevents the lifetime energy counters from decreasing.
+    ///
+    /// The inverter occasionally reports lower or zero totals, e.g. for ports missing in a reply.
+    /// Home Assistant treats a decreasing `total_increasing` sensor as meter reset, which ruins
+    /// the statistics, so the last known value is published instead.
+    fn guard_energy_totals(&mut self, dtu_sn: &str, json_payload: &mut serde_json::Value) {
+        let Some(values) = json_payload.as_object_mut() else {
+            return;
+        };
+        let last_totals = self.energy_totals.entry(dtu_sn.to_string()).or_default();
+        for (key, value) in values
+            .iter_mut()
+            .filter(|(key, _)| key.ends_with("energy_total"))
+        {
+            let Some(current) = value.as_f64() else {
+                continue;
+            };
+            match last_totals.get(key) {
+                Some(&last) if current < last => {
+                    warn!("Ignoring decreasing energy counter {key}: {current} < {last}");
+                    *value = last.into();
+                }
+                _ => {
+                    last_totals.insert(key.clone(), current);
+                }
+            }
+        }
     }
 }
 
+fn sensor_config_topic(config_topic: &str, sensor_config: &SensorConfig) -> String {
+    format!("{}/{}/config", config_topic, sensor_config.unique_id)
+}
+
 impl<MQTT: MqttWrapper> MetricPublisher for HomeAssistant<MQTT> {
+    fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
     fn publish(&mut self, hms_state: &HMSStateResponse) {
-        let config_topic = format!("homeassistant/sensor/hms_{}", hms_state.short_dtu_sn());
-        let state_topic = format!("solar/hms_{}/state", hms_state.short_dtu_sn());
+        self.change_detector
+            .reset_on_reconnect(self.client.connection_state());
+
+        let identifier =
+            hms_state.get_identifier(self.config.unique_id_scheme, &self.config.serial_aliases);
+        let config_topic = format!("homeassistant/sensor/{identifier}");
+        let state_topic = format!("solar/{identifier}/state");
 
-        let device_config = hms_state.create_sensor_configs(&state_topic);
+        let alias = hms_state.get_alias(&self.config.serial_aliases);
+        let device_config: Vec<SensorConfig> = hms_state
+            .create_sensor_configs(&state_topic, &identifier)
+            .into_iter()
+            .filter(|sensor_config| {
+                self.config
+                    .filter
+                    .is_included(&hms_state.dtu_sn, alias, &sensor_config.key)
+            })
+            .collect();
 
-        self.publish_configs(&config_topic, &device_config);
-        self.publish_states(hms_state, &state_topic);
+        let stale_topics = self.retained_topics.update(
+            &hms_state.dtu_sn,
+            device_config
+                .iter()
+                .map(|sensor_config| sensor_config_topic(&config_topic, sensor_config))
+                .chain([state_topic.clone()])
+                .collect(),
+        );
+        self.clear_topics(stale_topics);
+
+        let state_payload = self.get_state_payload(hms_state);
+        // only numbers are compared, the last update changes with every reply
+        let values = state_payload
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(key, value)| Some((key, value.as_f64()?)));
+        if !self.change_detector.is_changed(&hms_state.dtu_sn, values) {
+            debug!("Skipping unchanged state of {}", hms_state.dtu_sn);
+            return;
+        }
+
+        self.publish_configs(&config_topic, &device_config, &hms_state.dtu_sn);
+        let retain = self.config.retain.unwrap_or(true);
+        self.publish_json(&state_topic, state_payload, retain, &hms_state.dtu_sn);
     }
 }
 
@@ -67,74 +201,135 @@ impl HMSStateResponse {
         "HMS-WiFi".to_string()
     }
 
-    fn get_name(&self) -> String {
-        format!("Hoymiles {} {}", self.get_model(), self.short_dtu_sn())
+    fn get_name(&self, identifier: &str) -> String {
+        let name = identifier.strip_prefix("hms_").unwrap_or(identifier);
+        format!("Hoymiles {} {}", self.get_model(), name)
     }
 
-    fn short_dtu_sn(&self) -> String {
-        self.dtu_sn[..8].to_string()
+    /// Returns the identifier of the DTU used for the device, unique ids and topics.
+    ///
+    /// The identifier only contains characters allowed in Home Assistant discovery topics.
+    fn get_identifier(&self, scheme: UniqueIdScheme, aliases: &HashMap<String, String>) -> String {
+        let id = match scheme {
+            UniqueIdScheme::ShortSerial => self.dtu_sn.chars().take(8).collect(),
+            UniqueIdScheme::Serial => aliases
+                .get(&self.dtu_sn)
+                .unwrap_or(&self.dtu_sn)
+                .to_string(),
+        };
+        let id: String = id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if id.is_empty() {
+            "hms_unknown".to_string()
+        } else {
+            format!("hms_{id}")
+        }
     }
 
-    fn get_total_efficiency(&self) -> f32 {
-        let total_module_power: f32 = self
+    fn get_total_efficiency(&self) -> f64 {
+        let total_module_power: f64 = self
             .port_state
             .iter()
-            .map(|port| port.pv_power as f32)
+            .map(|port| port.pv_power as f64)
             .sum();
         if total_module_power > 0.0 {
-            self.pv_current_power as f32 / total_module_power * 100.0
+            self.pv_current_power as f64 / total_module_power * 100.0
         } else {
             0.0
         }
     }
 
+    /// Returns the ports belonging to the given inverter
+    fn get_inverter_ports<'a>(
+        &'a self,
+        inverter: &'a InverterState,
+    ) -> impl Iterator<Item = &'a PortState> {
+        // a single inverter owns all ports, even if the reply lacks the port serials
+        let single_inverter = self.inverter_state.len() == 1;
+        self.port_state
+            .iter()
+            .filter(move |port| single_inverter || port.pv_sn == inverter.inv_id)
+    }
+
+    /// Returns the lifetime energy of all ports in kWh
+    fn get_energy_total(&self) -> f64 {
+        self.port_state
+            .iter()
+            .map(|port| port.pv_energy_total as f64)
+            .sum::<f64>()
+            / 1000.0
+    }
+
+    fn get_last_update(&self) -> String {
+        DateTime::<Utc>::from_timestamp(self.time.into(), 0)
+            .unwrap_or_default()
+            .to_rfc3339()
+    }
+
     fn to_json_payload(&self) -> serde_json::Value {
         // when modifying this function, modify the sensor config in create_device_config accordingly
         let mut json = json!({
             "dtu_sn": self.dtu_sn,
-            "pv_current_power": format!("{:.2}", self.pv_current_power as f32 * 0.1),
+            "last_update": self.get_last_update(),
+            "pv_current_power": self.pv_current_power as f64 / 10.0,
             "pv_daily_yield": self.pv_daily_yield,
-            "efficiency": format!("{:.2}", self.get_total_efficiency())
+            "pv_energy_total": self.get_energy_total(),
+            "efficiency": self.get_total_efficiency()
         });
 
         // Convert each PortState to json
         for port in self.port_state.iter() {
-            json[format!("pv_{}_vol", port.pv_port)] =
-                format!("{:.2}", port.pv_vol as f32 * 0.1).into();
-            json[format!("pv_{}_cur", port.pv_port)] =
-                format!("{:.2}", port.pv_cur as f32 * 0.01).into();
-            json[format!("pv_{}_power", port.pv_port)] =
-                format!("{:.2}", port.pv_power as f32 * 0.1).into();
+            json[format!("pv_{}_vol", port.pv_port)] = (port.pv_vol as f64 / 10.0).into();
+            json[format!("pv_{}_cur", port.pv_port)] = (port.pv_cur as f64 / 100.0).into();
+            json[format!("pv_{}_power", port.pv_port)] = (port.pv_power as f64 / 10.0).into();
             json[format!("pv_{}_energy_total", port.pv_port)] = port.pv_energy_total.into();
             json[format!("pv_{}_daily_yield", port.pv_port)] = port.pv_daily_yield.into();
         }
         // Convert each InverterState to json (for a HMS-XXXW-2T, there is only one inverter)
         for inverter in self.inverter_state.iter() {
             json[format!("inv_{}_grid_voltage", inverter.port_id)] =
-                format!("{:.2}", inverter.grid_voltage as f32 * 0.1).into();
+                (inverter.grid_voltage as f64 / 10.0).into();
             json[format!("inv_{}_grid_freq", inverter.port_id)] =
-                format!("{:.2}", inverter.grid_freq as f32 * 0.01).into();
+                (inverter.grid_freq as f64 / 100.0).into();
             json[format!("inv_{}_pv_current_power", inverter.port_id)] =
-                format!("{:.2}", inverter.pv_current_power as f32 * 0.1).into();
+                (inverter.pv_current_power as f64 / 10.0).into();
             json[format!("inv_{}_temperature", inverter.port_id)] =
-                format!("{:.2}", inverter.temperature as f32 * 0.1).into();
+                (inverter.temperature as f64 / 10.0).into();
+            json[format!("inv_{}_energy_total", inverter.port_id)] = (self
+                .get_inverter_ports(inverter)
+                .map(|port| port.pv_energy_total as f64)
+                .sum::<f64>()
+                / 1000.0)
+                .into();
         }
 
         json
     }
 
-    fn create_sensor_configs(&self, state_topic: &str) -> Vec<SensorConfig> {
+    fn create_sensor_configs(&self, state_topic: &str, identifier: &str) -> Vec<SensorConfig> {
         let mut sensors = Vec::new();
 
         let device_config = DeviceConfig::new(
-            self.get_name(),
+            self.get_name(identifier),
             self.get_model(),
-            Vec::from([format!("hms_{}", self.short_dtu_sn())]),
+            Vec::from([identifier.to_string()]),
         );
 
         // Sensors for the whole inverter
         sensors.extend([
-            SensorConfig::string(state_topic, &device_config, "DTU Serial Number", "dtu_sn"),
+            SensorConfig::string(state_topic, &device_config, "DTU Serial Number", "dtu_sn")
+                .with_icon("mdi:identifier")
+                .diagnostic(),
+            SensorConfig::timestamp(state_topic, &device_config, "Last Update", "last_update")
+                .diagnostic(),
             SensorConfig::power(
                 state_topic,
                 &device_config,
@@ -147,6 +342,12 @@ impl HMSStateResponse {
                 "Total Daily Yield",
                 "pv_daily_yield",
             ),
+            SensorConfig::lifetime_energy(
+                state_topic,
+                &device_config,
+                "Lifetime Energy",
+                "pv_energy_total",
+            ),
             SensorConfig::efficiency(state_topic, &device_config, "Efficiency", "efficiency"),
         ]);
 
@@ -200,7 +401,8 @@ impl HMSStateResponse {
                     &device_config,
                     &format!("Inverter {} Temperature", idx),
                     &format!("inv_{}_temperature", idx),
-                ),
+                )
+                .diagnostic(),
                 SensorConfig::voltage(
                     state_topic,
                     &device_config,
@@ -213,8 +415,115 @@ impl HMSStateResponse {
                     &format!("Inverter {} Grid Frequency", idx),
                     &format!("inv_{}_grid_freq", idx),
                 ),
+                SensorConfig::lifetime_energy(
+                    state_topic,
+                    &device_config,
+                    &format!("Inverter {} Lifetime Energy", idx),
+                    &format!("inv_{}_energy_total", idx),
+                ),
             ]);
         }
         sensors
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::protos::hoymiles::RealData::{HMSStateResponse, InverterState, PortState};
+    use crate::targets::mqtt::mqtt_config::UniqueIdScheme;
+
+    fn state_with_serial(dtu_sn: &str) -> HMSStateResponse {
+        HMSStateResponse {
+            dtu_sn: dtu_sn.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_json_payload_is_numeric() {
+        let state = HMSStateResponse {
+            dtu_sn: "1234567890".into(),
+            time: 1_700_000_000,
+            pv_current_power: 1234,
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_vol: 305,
+                pv_cur: 412,
+                pv_power: 1260,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let payload = state.to_json_payload();
+
+        assert_eq!(payload["pv_current_power"], 123.4);
+        assert_eq!(payload["pv_1_vol"], 30.5);
+        assert_eq!(payload["pv_1_cur"], 4.12);
+        assert_eq!(payload["last_update"], "2023-11-14T22:13:20+00:00");
+    }
+
+    #[test]
+    fn test_short_serial_identifier_is_unchanged() {
+        let state = state_with_serial("4143A0123456");
+        let identifier = state.get_identifier(UniqueIdScheme::ShortSerial, &HashMap::new());
+        assert_eq!(identifier, "hms_4143A012");
+        assert_eq!(
+            state.create_sensor_configs("state", &identifier)[0].unique_id,
+            "hms_4143A012_dtu_sn"
+        );
+    }
+
+    #[test]
+    fn test_identifier_of_odd_serials() {
+        let aliases = HashMap::from([("4143A0123456".to_string(), "roof/east".to_string())]);
+        for scheme in [UniqueIdScheme::ShortSerial, UniqueIdScheme::Serial] {
+            assert_eq!(
+                state_with_serial("").get_identifier(scheme, &aliases),
+                "hms_unknown"
+            );
+            assert_eq!(
+                state_with_serial("123").get_identifier(scheme, &aliases),
+                "hms_123"
+            );
+            assert_eq!(
+                state_with_serial("äb#+c").get_identifier(scheme, &aliases),
+                "hms__b__c"
+            );
+        }
+        assert_eq!(
+            state_with_serial("4143A0123456").get_identifier(UniqueIdScheme::Serial, &aliases),
+            "hms_roof_east"
+        );
+        assert_eq!(
+            state_with_serial("4143A0123457").get_identifier(UniqueIdScheme::Serial, &aliases),
+            "hms_4143A0123457"
+        );
+    }
+
+    #[test]
+    fn test_lifetime_energy_per_inverter() {
+        let port = |pv_sn, pv_port, pv_energy_total| PortState {
+            pv_sn,
+            pv_port,
+            pv_energy_total,
+            ..Default::default()
+        };
+        let inverter = |inv_id, port_id| InverterState {
+            inv_id,
+            port_id,
+            ..Default::default()
+        };
+        let state = HMSStateResponse {
+            inverter_state: vec![inverter(11, 1), inverter(22, 3)],
+            port_state: vec![port(11, 1, 1000), port(11, 2, 500), port(22, 3, 250)],
+            ..Default::default()
+        };
+        let payload = state.to_json_payload();
+
+        assert_eq!(payload["pv_energy_total"], 1.75);
+        assert_eq!(payload["inv_1_energy_total"], 1.5);
+        assert_eq!(payload["inv_3_energy_total"], 0.25);
+    }
+}
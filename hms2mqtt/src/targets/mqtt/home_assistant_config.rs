@@ -36,6 +36,8 @@ impl DeviceConfig {
 ///
 #[derive(Serialize)]
 pub struct SensorConfig {
+    #[serde(skip)]
+    pub key: String, // The key of the value in the state payload.
     pub unique_id: String,  //  A globally unique identifier for the sensor.
     name: String,           // The name of the sensor.
     state_topic: String,    // The MQTT topic where sensor readings will be published.
@@ -48,21 +50,28 @@ pub struct SensorConfig {
     device_class: Option<String>, // The type/class of the sensor, e.g. energy, power, temperature, etc.
     #[serde(skip_serializing_if = "Option::is_none")]
     state_class: Option<String>, // The type/class of the state, e.g. measurement, total_increasing, etc.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggested_display_precision: Option<u8>, // The number of decimals shown in the frontend.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<String>, // The category of the entity, e.g. diagnostic or config.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>, // The icon shown in the frontend, e.g. mdi:solar-power.
 }
 
 impl SensorConfig {
     pub fn new_sensor(
         state_topic: &str,
         device_config: &DeviceConfig,
-        unique_id: &str,
+        key: &str,
         name: &str,
         device_class: Option<String>,
         unit_of_measurement: Option<String>,
         state_class: Option<String>,
     ) -> Self {
-        let value_template = format!("{{{{ value_json.{} }}}}", unique_id);
-        let unique_id = format!("{}_{}", device_config.identifiers[0], unique_id);
+        let value_template = format!("{{{{ value_json.{} }}}}", key);
+        let unique_id = format!("{}_{}", device_config.identifiers[0], key);
         SensorConfig {
+            key: key.to_string(),
             unique_id,
             name: name.to_string(),
             state_topic: state_topic.to_string(),
@@ -71,13 +80,52 @@ impl SensorConfig {
             value_template,
             device: device_config.clone(),
             state_class,
+            suggested_display_precision: None,
+            entity_category: None,
+            icon: None,
         }
     }
 
+    /// Sets the number of decimals Home Assistant shows for this sensor.
+    pub fn with_precision(mut self, precision: u8) -> Self {
+        self.suggested_display_precision = Some(precision);
+        self
+    }
+
+    /// Sets the icon of the sensor.
+    pub fn with_icon(mut self, icon: &str) -> Self {
+        self.icon = Some(icon.to_string());
+        self
+    }
+
+    /// Marks the sensor as diagnostic entity, hiding it from the default dashboards.
+    pub fn diagnostic(mut self) -> Self {
+        self.entity_category = Some("diagnostic".to_string());
+        self
+    }
+
     pub fn string(state_topic: &str, device_config: &DeviceConfig, name: &str, key: &str) -> Self {
         Self::new_sensor(state_topic, device_config, key, name, None, None, None)
     }
 
+    pub fn timestamp(
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+    ) -> Self {
+        Self::new_sensor(
+            state_topic,
+            device_config,
+            key,
+            name,
+            Some("timestamp".to_string()),
+            None,
+            None,
+        )
+        .with_icon("mdi:clock-outline")
+    }
+
     pub fn power(state_topic: &str, device_config: &DeviceConfig, name: &str, key: &str) -> Self {
         Self::new_sensor(
             state_topic,
@@ -88,6 +136,8 @@ impl SensorConfig {
             Some("W".to_string()),
             Some("measurement".to_string()),
         )
+        .with_precision(1)
+        .with_icon("mdi:solar-power")
     }
 
     pub fn energy(state_topic: &str, device_config: &DeviceConfig, name: &str, key: &str) -> Self {
@@ -100,6 +150,28 @@ impl SensorConfig {
             Some("Wh".to_string()),
             Some("total_increasing".to_string()),
         )
+        .with_precision(0)
+        .with_icon("mdi:lightning-bolt")
+    }
+
+    /// Energy counter in kWh usable by the Home Assistant energy dashboard
+    pub fn lifetime_energy(
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+    ) -> Self {
+        Self::new_sensor(
+            state_topic,
+            device_config,
+            key,
+            name,
+            Some("energy".to_string()),
+            Some("kWh".to_string()),
+            Some("total_increasing".to_string()),
+        )
+        .with_precision(3)
+        .with_icon("mdi:solar-power-variant")
     }
 
     pub fn voltage(state_topic: &str, device_config: &DeviceConfig, name: &str, key: &str) -> Self {
@@ -112,6 +184,8 @@ impl SensorConfig {
             Some("V".to_string()),
             Some("measurement".to_string()),
         )
+        .with_precision(1)
+        .with_icon("mdi:flash-triangle")
     }
 
     pub fn current(state_topic: &str, device_config: &DeviceConfig, name: &str, key: &str) -> Self {
@@ -124,6 +198,8 @@ impl SensorConfig {
             Some("A".to_string()),
             Some("measurement".to_string()),
         )
+        .with_precision(2)
+        .with_icon("mdi:current-dc")
     }
 
     pub fn temperature(
@@ -141,6 +217,8 @@ impl SensorConfig {
             Some("°C".to_string()),
             Some("measurement".to_string()),
         )
+        .with_precision(1)
+        .with_icon("mdi:thermometer")
     }
 
     pub fn efficiency(
@@ -158,6 +236,8 @@ impl SensorConfig {
             Some("%".to_string()),
             Some("measurement".to_string()),
         )
+        .with_precision(2)
+        .with_icon("mdi:percent")
     }
 
     pub fn frequency(
@@ -175,5 +255,7 @@ impl SensorConfig {
             Some("Hz".to_string()),
             Some("measurement".to_string()),
         )
+        .with_precision(2)
+        .with_icon("mdi:sine-wave")
     }
 }
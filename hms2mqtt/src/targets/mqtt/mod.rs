@@ -3,5 +3,8 @@ pub mod home_assistant_config;
 #[allow(clippy::module_inception)]
 pub mod mqtt;
 pub mod mqtt_config;
+#[cfg(test)]
+pub(crate) mod mqtt_stub;
 pub mod mqtt_wrapper;
+pub mod retained_topics;
 pub mod simple_mqtt;
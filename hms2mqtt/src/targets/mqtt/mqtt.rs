@@ -1,48 +1,448 @@
 use crate::{
-    protos::hoymiles::RealData::HMSStateResponse,
+    protos::hoymiles::RealData::{HMSStateResponse, InverterState, PortState},
+    sources::metric::{Metric, MetricLevel},
     targets::{
+        change_detector::ChangeDetector,
         metric_publisher::MetricPublisher,
         mqtt::{
-            mqtt_config::MqttConfig,
-            mqtt_wrapper::{MqttWrapper, QoS},
+            mqtt_config::{MqttConfig, OutputFormat},
+            mqtt_wrapper::{MqttWrapper, PublishProperties, QoS},
+            retained_topics::RetainedTopics,
         },
+        template::MetricVariable,
     },
 };
 
+use anyhow::bail;
 use log::{debug, warn};
-use std::sync::mpsc::channel;
+use serde_json::{json, Map, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::mpsc::channel,
+};
 
 pub struct Mqtt<MQTT: MqttWrapper> {
     client: MQTT,
     config: MqttConfig,
+    retained_topics: RetainedTopics,
+    change_detector: ChangeDetector,
 }
 
 impl<MQTT: MqttWrapper> Mqtt<MQTT> {
-    pub fn new(config: &MqttConfig) -> Self {
+    pub fn new(config: &MqttConfig) -> anyhow::Result<Self> {
         let (tx, _rx) = channel();
-        let client = MQTT::new(config, tx);
-        Self {
+        let client = MQTT::new(config, tx)?;
+        if config.output_format != OutputFormat::Topics
+            && (config.topic_template.is_some() || config.payload_template.is_some())
+        {
+            warn!("Topic and payload templates are ignored for JSON output");
+        }
+        let mqtt = Self {
             client,
             config: config.clone(),
+            retained_topics: RetainedTopics::load_or_warn(config.state_file.as_deref()),
+            change_detector: ChangeDetector::new(config.publish_on_change.clone()),
+        };
+        mqtt.check_topic_template()?;
+        Ok(mqtt)
+    }
+
+    /// Rejects topic templates that publish several values to the same topic or render
+    /// wildcards, by rendering the topics of a DTU with two inverters and two ports
+    fn check_topic_template(&self) -> anyhow::Result<()> {
+        if self.config.topic_template.is_none() || self.config.output_format != OutputFormat::Topics
+        {
+            return Ok(());
+        }
+        // filters may differ per DTU
+        let serials = self
+            .config
+            .serial_aliases
+            .keys()
+            .cloned()
+            .chain(["0".to_string()]);
+        for dtu_sn in serials {
+            let hms_state = HMSStateResponse {
+                dtu_sn,
+                inverter_state: (1..=2)
+                    .map(|inv_id| InverterState {
+                        inv_id,
+                        ..Default::default()
+                    })
+                    .collect(),
+                port_state: (1..=2)
+                    .map(|pv_port| PortState {
+                        pv_port,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            };
+            let metrics = self.get_metrics(&hms_state);
+            let mut topics = HashMap::new();
+            for (metric, (topic, _)) in metrics.iter().zip(self.render_topics(&hms_state, &metrics))
+            {
+                if topic.contains(['+', '#']) {
+                    bail!("topic_template renders the topic '{topic}' containing the wildcard '+' or '#'");
+                }
+                if let Some(other) = topics.insert(topic.clone(), metric.path()) {
+                    bail!(
+                        "topic_template renders the same topic '{topic}' for {other} and {}, add variables like {{{{inverter}}}}, {{{{port}}}} or {{{{metric}}}}",
+                        metric.path()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes every retained topic this target remembers publishing and closes the connection.
+    pub fn purge(mut self) -> anyhow::Result<()> {
+        self.retained_topics
+            .purge(&mut self.client, self.config.qos)
+    }
+
+    fn clear_topics(&mut self, topics: Vec<String>) {
+        for topic in topics {
+            debug!("Removing obsolete topic {topic}");
+            if let Err(e) = self.client.publish(topic, self.config.qos, true, "") {
+                warn!("mqtt error: {e:?}")
+            }
+        }
+    }
+
+    fn get_metrics(&self, hms_state: &HMSStateResponse) -> Vec<Metric> {
+        let alias = hms_state.get_alias(&self.config.serial_aliases);
+        hms_state
+            .get_metrics()
+            .into_iter()
+            .filter(|metric| {
+                self.config
+                    .filter
+                    .is_included(&hms_state.dtu_sn, alias, &metric.path())
+            })
+            .collect()
+    }
+
+    /// Returns the topics and payloads of the metrics, in the same order for the `Topics` format
+    fn get_payloads(
+        &self,
+        hms_state: &HMSStateResponse,
+        metrics: &[Metric],
+    ) -> Vec<(String, String)> {
+        match self.config.output_format {
+            OutputFormat::Topics => self.render_topics(hms_state, metrics),
+            OutputFormat::Json | OutputFormat::JsonPerComponent => {
+                let per_component = self.config.output_format == OutputFormat::JsonPerComponent;
+                let base_topic = hms_state
+                    .get_base_topic(Some(&self.config.base_topic), &self.config.serial_aliases);
+                hms_state
+                    .get_json_documents(&base_topic, metrics, per_component)
+                    .into_iter()
+                    .map(|(topic, document)| (topic, document.to_string()))
+                    .collect()
+            }
+        }
+    }
+
+    fn render_topics(
+        &self,
+        hms_state: &HMSStateResponse,
+        metrics: &[Metric],
+    ) -> Vec<(String, String)> {
+        let aliases = &self.config.serial_aliases;
+        let alias = hms_state.get_alias(aliases);
+        let base_topic = hms_state.get_base_topic(Some(&self.config.base_topic), aliases);
+        metrics
+            .iter()
+            .map(|metric| {
+                let lookup = |variable: MetricVariable| {
+                    variable.get_value(&self.config.base_topic, alias, hms_state, metric)
+                };
+                let topic = match &self.config.topic_template {
+                    // variables of other components are empty, skip the resulting empty levels
+                    Some(template) => template
+                        .render(lookup)
+                        .split('/')
+                        .filter(|level| !level.is_empty())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    None => format!("{}/{}", base_topic, metric.path()),
+                };
+                let payload = match &self.config.payload_template {
+                    Some(template) => template.render(lookup),
+                    None => (metric.value as f32).to_string(),
+                };
+                (topic, payload)
+            })
+            .collect()
+    }
+
+    /// Returns the QoS, retain flag and properties of each payload, or `None` if it is unchanged
+    fn get_publish_options(
+        &mut self,
+        dtu_sn: &str,
+        metrics: &[Metric],
+        payloads: usize,
+    ) -> Vec<Option<(QoS, bool, PublishProperties)>> {
+        let serial = ("serial".to_string(), dtu_sn.to_string());
+        match self.config.output_format {
+            OutputFormat::Topics => metrics
+                .iter()
+                .map(|metric| {
+                    let key = metric.path();
+                    let (qos, retain) = self.config.get_publish_options(&key);
+                    let properties = PublishProperties {
+                        // templates may render anything
+                        content_type: self
+                            .config
+                            .payload_template
+                            .is_none()
+                            .then(|| "text/plain".into()),
+                        message_expiry: self.config.get_message_expiry(metric),
+                        user_properties: [serial.clone()]
+                            .into_iter()
+                            .chain(metric.unit.map(|unit| ("unit".into(), unit.into())))
+                            .collect(),
+                    };
+                    self.change_detector
+                        .is_changed(dtu_sn, [(key, metric.value)])
+                        .then_some((qos, retain, properties))
+                })
+                .collect(),
+            // documents are published together to keep them consistent
+            OutputFormat::Json | OutputFormat::JsonPerComponent => {
+                let changed = self.change_detector.is_changed(
+                    dtu_sn,
+                    metrics.iter().map(|metric| (metric.path(), metric.value)),
+                );
+                // documents contain energy counters as well, so they do not expire
+                let properties = PublishProperties {
+                    content_type: Some("application/json".into()),
+                    message_expiry: None,
+                    user_properties: vec![serial],
+                };
+                let options = (
+                    self.config.qos,
+                    self.config.retain.unwrap_or(true),
+                    properties,
+                );
+                vec![changed.then_some(options); payloads]
+            }
         }
     }
 }
 
 impl<MQTT: MqttWrapper> MetricPublisher for Mqtt<MQTT> {
+    fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
     fn publish(&mut self, hms_state: &HMSStateResponse) {
-        let topic_payload_pairs =
-            hms_state.get_topics(Some(&self.config.base_topic), &self.config.serial_aliases);
+        self.change_detector
+            .reset_on_reconnect(self.client.connection_state());
 
+        let metrics = self.get_metrics(hms_state);
+        let topic_payload_pairs = self.get_payloads(hms_state, &metrics);
+
+        let stale_topics = self.retained_topics.update(
+            &hms_state.dtu_sn,
+            topic_payload_pairs
+                .iter()
+                .map(|(topic, _)| topic.clone())
+                .collect(),
+        );
+        self.clear_topics(stale_topics);
+
+        let publish_options =
+            self.get_publish_options(&hms_state.dtu_sn, &metrics, topic_payload_pairs.len());
         topic_payload_pairs
             .into_iter()
-            .for_each(|(topic, payload)| {
+            .zip(publish_options)
+            .filter_map(|(pair, options)| Some((pair, options?)))
+            .for_each(|((topic, payload), (qos, retain, properties))| {
                 debug!("Publishing to {} value: {}", topic, payload);
-                if let Err(e) =
-                    self.client
-                        .publish(topic, QoS::AtMostOnce, true, payload.to_string())
+                if let Err(e) = self
+                    .client
+                    .publish_with_properties(topic, qos, retain, payload, properties)
                 {
                     warn!("mqtt error: {e:?}")
                 }
             });
     }
 }
+
+fn metrics_to_json<'a>(metrics: impl Iterator<Item = &'a Metric>) -> Value {
+    metrics
+        .map(|metric| {
+            (
+                metric.name.to_string(),
+                json!({ "value": metric.value, "unit": metric.unit }),
+            )
+        })
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+impl HMSStateResponse {
+    /// Returns a JSON document with the given values of the DTU below `base_topic/state`.
+    ///
+    /// With `per_component` every inverter and port additionally gets its own document
+    /// below its topic, e.g. `base_topic/port/1/state`.
+    pub(crate) fn get_json_documents(
+        &self,
+        base_topic: &str,
+        metrics: &[Metric],
+        per_component: bool,
+    ) -> Vec<(String, Value)> {
+        let mut components: BTreeMap<MetricLevel, Vec<&Metric>> = BTreeMap::new();
+        for metric in metrics {
+            components.entry(metric.level).or_default().push(metric);
+        }
+
+        let mut dtu_document = json!({
+            "dtu_sn": self.dtu_sn,
+            "timestamp": self.time,
+            "values": {},
+            "inverters": {},
+            "ports": {},
+        });
+        let mut documents = Vec::new();
+        for (level, metrics) in components {
+            let values = metrics_to_json(metrics.into_iter());
+            let (key, component, id) = match level {
+                MetricLevel::Dtu => {
+                    dtu_document["values"] = values;
+                    continue;
+                }
+                MetricLevel::Inverter(id) => ("inverters", "inverter", id.to_string()),
+                MetricLevel::Port(port) => ("ports", "port", port.to_string()),
+            };
+            if per_component {
+                documents.push((
+                    format!("{base_topic}/{component}/{id}/state"),
+                    json!({
+                        "dtu_sn": self.dtu_sn,
+                        "timestamp": self.time,
+                        "values": values,
+                    }),
+                ));
+            }
+            dtu_document[key][id] = values;
+        }
+        documents.push((format!("{base_topic}/state"), dtu_document));
+        documents
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Mqtt;
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, InverterState, PortState},
+        targets::{
+            metric_publisher::MetricPublisher,
+            mqtt::{mqtt_config::MqttConfig, mqtt_stub::MqttStub, mqtt_wrapper::ConnectionState},
+        },
+    };
+
+    fn new_mqtt(extra: &str) -> anyhow::Result<Mqtt<MqttStub>> {
+        let config: MqttConfig = serde_yaml::from_str(&format!("host: '::1'\n{extra}")).unwrap();
+        Mqtt::new(&config)
+    }
+
+    #[test]
+    fn test_duplicate_template_topics() {
+        assert!(new_mqtt("topic_template: '{{base_topic}}/{{alias}}/{{path}}'").is_ok());
+        assert!(new_mqtt(
+            "topic_template: '{{base_topic}}/{{component}}/{{inverter}}{{port}}/{{metric}}'"
+        )
+        .is_ok());
+
+        let error = new_mqtt("topic_template: '{{base_topic}}/{{inverter}}/{{metric}}'")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("same topic"), "{error}");
+        assert!(new_mqtt("topic_template: '{{base_topic}}/{{port}}'").is_err());
+
+        // the filter only leaves values of the ports, whose metric names are unique
+        assert!(new_mqtt(
+            "topic_template: '{{base_topic}}/{{port}}/{{metric}}'\nfilter: {include: ['port/**']}"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_wildcard_template_topics() {
+        let error = new_mqtt("topic_template: '{{base_topic}}/+/{{path}}'")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("wildcard"), "{error}");
+        assert!(new_mqtt("topic_template: '{{base_topic}}/{{path}}/#'").is_err());
+        assert!(
+            new_mqtt("base_topic: 'solar/#'\ntopic_template: '{{base_topic}}/{{path}}'").is_err()
+        );
+    }
+
+    #[test]
+    fn test_json_documents() {
+        let state = HMSStateResponse {
+            dtu_sn: "123".into(),
+            time: 1_700_000_000,
+            pv_current_power: 1234,
+            inverter_state: vec![InverterState {
+                inv_id: 42,
+                temperature: 215,
+                ..Default::default()
+            }],
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_power: 617,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let documents = state.get_json_documents("hms/dtu/123", &state.get_metrics(), false);
+        assert_eq!(documents.len(), 1);
+        let (topic, document) = &documents[0];
+        assert_eq!(topic, "hms/dtu/123/state");
+        assert_eq!(document["timestamp"], 1_700_000_000);
+        assert_eq!(document["values"]["current_power"]["value"], 123.4);
+        assert_eq!(document["values"]["current_power"]["unit"], "W");
+        assert_eq!(document["inverters"]["42"]["temperature"]["value"], 21.5);
+        assert_eq!(document["ports"]["1"]["power"]["value"], 61.7);
+
+        let documents = state.get_json_documents("hms/dtu/123", &state.get_metrics(), true);
+        let topics: Vec<_> = documents.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "hms/dtu/123/inverter/42/state",
+                "hms/dtu/123/port/1/state",
+                "hms/dtu/123/state"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_publish_again_after_reconnect() {
+        let mut mqtt = new_mqtt("publish_on_change: {}").unwrap();
+        let snapshot = HMSStateResponse {
+            dtu_sn: "123".into(),
+            pv_current_power: 1234,
+            ..Default::default()
+        };
+        mqtt.publish(&snapshot);
+        let published = mqtt.client.messages.len();
+        assert!(published > 0);
+
+        mqtt.publish(&snapshot);
+        assert_eq!(mqtt.client.messages.len(), published);
+
+        // the broker may have lost the retained values with the previous connection
+        mqtt.client.connection = ConnectionState::Connected(2);
+        mqtt.publish(&snapshot);
+        assert_eq!(mqtt.client.messages.len(), 2 * published);
+    }
+}
@@ -1,8 +1,19 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 
 use rand::{distr::Alphanumeric, rng, Rng};
 use serde::{Deserialize, Deserializer};
 
+use crate::{
+    sources::metric::Metric,
+    targets::{
+        change_detector::ChangeDetectionConfig,
+        metric_filter::{MetricFilter, Pattern},
+        mqtt::mqtt_wrapper::QoS,
+        offline_buffer::OfflineBufferConfig,
+        template::{MetricVariable, Template},
+    },
+};
+
 fn default_topic() -> String {
     "hms800wt2".into()
 }
@@ -27,7 +38,9 @@ struct SerialAlias {
     alias: String,
 }
 
-fn deserialize_alias<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+pub(crate) fn deserialize_alias<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, String>, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -38,6 +51,69 @@ where
     Ok(res)
 }
 
+/// Scheme used to derive the Home Assistant identifiers of a DTU
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UniqueIdScheme {
+    /// First 8 characters of the serial, as used by earlier versions. Keeps existing entities.
+    #[default]
+    ShortSerial,
+    /// The configured alias or the full serial
+    Serial,
+}
+
+/// Format of the values published by the MQTT target
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// One topic per value
+    #[default]
+    Topics,
+    /// One JSON document per DTU
+    Json,
+    /// One JSON document per DTU, inverter and port
+    JsonPerComponent,
+}
+
+/// MQTT protocol version used to connect to the broker
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtocolVersion {
+    /// MQTT 3.1.1
+    #[default]
+    V3,
+    /// MQTT 5, adds message properties like content type and expiry
+    V5,
+}
+
+fn default_status_qos() -> QoS {
+    QoS::ExactlyOnce
+}
+
+fn default_websocket_path() -> String {
+    "/mqtt".into()
+}
+
+/// Connects via `ws://` or, with `tls`, via `wss://` instead of plain MQTT
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebsocketConfig {
+    /// Path of the websocket endpoint on the broker or reverse proxy
+    #[serde(default = "default_websocket_path")]
+    pub path: String,
+    /// Additional headers of the upgrade request, e.g. for authentication at a proxy
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// Publish options for the matching metrics
+#[derive(Debug, Deserialize, Clone)]
+pub struct PublishOverride {
+    pub metrics: Pattern,
+    pub qos: Option<QoS>,
+    pub retain: Option<bool>,
+    pub message_expiry: Option<u32>,
+}
+
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct MqttConfig {
     pub host: String,
@@ -45,6 +121,22 @@ pub struct MqttConfig {
     pub username: Option<String>,
     pub password: Option<String>,
     pub tls: Option<bool>,
+    /// PEM file with the CA certificates to trust instead of the system certificates
+    pub ca_file: Option<PathBuf>,
+    /// PEM file with the client certificate chain, requires `client_key`
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate
+    pub client_key: Option<PathBuf>,
+    /// Protocols offered via ALPN, e.g. `x-amzn-mqtt-ca` for AWS IoT on port 443
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    /// Skip the verification of the broker certificate. Only meant for testing.
+    #[serde(default)]
+    pub insecure: bool,
+    /// Use MQTT over websockets, the default port becomes 80 or 443 with `tls`
+    pub websocket: Option<WebsocketConfig>,
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
     #[serde(default = "default_topic")]
     pub base_topic: String,
     #[serde(default = "default_client_id")]
@@ -52,11 +144,86 @@ pub struct MqttConfig {
     /// Maps serials to an alias
     #[serde(deserialize_with = "deserialize_alias", default)]
     pub serial_aliases: HashMap<String, String>,
+    /// File to remember the published retained topics in, used to clean up obsolete ones
+    pub state_file: Option<PathBuf>,
+    /// Scheme for the Home Assistant unique ids
+    #[serde(default)]
+    pub unique_id_scheme: UniqueIdScheme,
+    /// Nest the simple MQTT topics below the serial or alias of each DTU instead of the flat legacy layout
+    #[serde(default)]
+    pub per_dtu_topics: bool,
+    /// Format of the values published by the MQTT target
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Topic of each value, e.g. `{{base_topic}}/{{alias}}/{{path}}`
+    pub topic_template: Option<Template<MetricVariable>>,
+    /// Payload of each value, e.g. `{"value": {{value}}, "unit": "{{unit}}"}`
+    pub payload_template: Option<Template<MetricVariable>>,
+    /// Selects the published values
+    #[serde(default)]
+    pub filter: MetricFilter,
+    /// Only publish values that changed, publishes everything if not set
+    pub publish_on_change: Option<ChangeDetectionConfig>,
+    /// QoS of the published messages, 0 if not set
+    #[serde(default)]
+    pub qos: QoS,
+    /// Whether the published messages are retained, true if not set
+    pub retain: Option<bool>,
+    /// QoS of the `online`/`offline` messages on the status topic, 2 if not set
+    #[serde(default = "default_status_qos")]
+    pub status_qos: QoS,
+    /// Publish options for single metrics, the first matching one applies
+    #[serde(default)]
+    pub publish_overrides: Vec<PublishOverride>,
+    /// Seconds after which an MQTT v5 broker discards instantaneous values like power, so
+    /// stale readings disappear when publishing stops. Energy counters do not expire.
+    pub message_expiry: Option<u32>,
+    /// Stores snapshots on disk while the broker is unreachable
+    pub offline_buffer: Option<OfflineBufferConfig>,
+}
+
+impl MqttConfig {
+    fn get_publish_override(&self, key: &str) -> Option<&PublishOverride> {
+        self.publish_overrides
+            .iter()
+            .find(|publish_override| publish_override.metrics.is_match(key))
+    }
+
+    /// Returns the QoS and retain flag for the metric with the given key
+    pub fn get_publish_options(&self, key: &str) -> (QoS, bool) {
+        let retain = self.retain.unwrap_or(true);
+        match self.get_publish_override(key) {
+            Some(publish_override) => (
+                publish_override.qos.unwrap_or(self.qos),
+                publish_override.retain.unwrap_or(retain),
+            ),
+            None => (self.qos, retain),
+        }
+    }
+
+    /// Returns the message expiry for the given metric, only instantaneous values expire by default
+    pub fn get_message_expiry(&self, metric: &Metric) -> Option<u32> {
+        self.get_key_message_expiry(&metric.path(), metric.unit)
+    }
+
+    /// Returns the message expiry for the value with the given key and unit
+    pub fn get_key_message_expiry(&self, key: &str, unit: Option<&str>) -> Option<u32> {
+        self.get_publish_override(key)
+            .and_then(|publish_override| publish_override.message_expiry)
+            .or_else(|| {
+                self.message_expiry
+                    .filter(|_| Metric::is_instantaneous_unit(unit))
+            })
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::MqttConfig;
+    use super::{MqttConfig, ProtocolVersion, UniqueIdScheme};
+    use crate::{
+        sources::metric::{Metric, MetricLevel},
+        targets::mqtt::mqtt_wrapper::QoS,
+    };
 
     #[test]
     fn test_deserialize() {
@@ -71,5 +238,57 @@ mod test {
         assert!(conf.client_id.starts_with("hms-mqtt-"));
         assert_eq!(conf.serial_aliases.len(), 1);
         assert_eq!(conf.serial_aliases.get("123").unwrap(), "test_alias");
+        assert_eq!(conf.unique_id_scheme, UniqueIdScheme::ShortSerial);
+        assert_eq!(conf.protocol_version, ProtocolVersion::V3);
+        assert_eq!(conf.qos, QoS::AtMostOnce);
+        assert_eq!(conf.status_qos, QoS::ExactlyOnce);
+    }
+
+    #[test]
+    fn test_publish_overrides() {
+        let conf: MqttConfig = serde_yaml::from_str(
+            r#"
+            host: "::1"
+            qos: 1
+            publish_overrides:
+              - metrics: "port/*/power"
+                qos: 0
+                retain: false
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            conf.get_publish_options("port/1/power"),
+            (QoS::AtMostOnce, false)
+        );
+        assert_eq!(
+            conf.get_publish_options("port/1/energy"),
+            (QoS::AtLeastOnce, true)
+        );
+        assert!(serde_yaml::from_str::<MqttConfig>("{host: '::1', qos: 3}").is_err());
+    }
+
+    #[test]
+    fn test_message_expiry() {
+        let conf: MqttConfig = serde_yaml::from_str(
+            r#"
+            host: "::1"
+            protocol_version: v5
+            message_expiry: 90
+            publish_overrides:
+              - metrics: "inverter_local_time"
+                message_expiry: 3600
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(conf.protocol_version, ProtocolVersion::V5);
+        let power = Metric::new(MetricLevel::Port(1), "power", 61.7, "W");
+        let energy = Metric::new(MetricLevel::Port(1), "energy", 1234., "Wh");
+        let time = Metric::new(MetricLevel::Dtu, "inverter_local_time", 1e9, "s");
+        assert_eq!(conf.get_message_expiry(&power), Some(90));
+        assert_eq!(conf.get_message_expiry(&energy), None);
+        assert_eq!(conf.get_message_expiry(&time), Some(3600));
     }
 }
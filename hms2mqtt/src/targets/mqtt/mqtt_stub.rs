@@ -0,0 +1,90 @@
+//! MQTT client recording the published messages for testing the MQTT targets.
+
+use std::{sync::mpsc::Sender, time::Duration};
+
+use anyhow::bail;
+
+use super::{
+    mqtt_config::MqttConfig,
+    mqtt_wrapper::{ConnectionState, MqttWrapper, PublishEvent, PublishProperties, QoS},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub properties: PublishProperties,
+}
+
+/// Connected from the start, tests change `connection` to simulate outages and reconnects.
+pub struct MqttStub {
+    pub messages: Vec<Message>,
+    pub connection: ConnectionState,
+    pub disconnected: bool,
+    /// Publishing to this topic fails
+    pub failing_topic: Option<String>,
+}
+
+impl MqttWrapper for MqttStub {
+    fn subscribe(&mut self, _topic: &str, _qos: QoS) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn publish<S, V>(&mut self, topic: S, qos: QoS, retain: bool, payload: V) -> anyhow::Result<()>
+    where
+        S: Clone + Into<String>,
+        V: Clone + Into<Vec<u8>>,
+    {
+        self.publish_with_properties(topic, qos, retain, payload, PublishProperties::default())
+    }
+
+    fn publish_with_properties<S, V>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+        properties: PublishProperties,
+    ) -> anyhow::Result<()>
+    where
+        S: Clone + Into<String>,
+        V: Clone + Into<Vec<u8>>,
+    {
+        let topic = topic.into();
+        if self.failing_topic.as_ref() == Some(&topic) {
+            bail!("failed to publish to {topic}");
+        }
+        self.messages.push(Message {
+            topic,
+            payload: String::from_utf8_lossy(&payload.into()).into_owned(),
+            qos,
+            retain,
+            properties,
+        });
+        Ok(())
+    }
+
+    fn connection_state(&self) -> ConnectionState {
+        self.connection
+    }
+
+    fn wait_until_connected(&self, _timeout: Duration) -> bool {
+        self.is_connected()
+    }
+
+    fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.disconnected = true;
+        Ok(())
+    }
+
+    fn new(_config: &MqttConfig, _pub_tx: Sender<PublishEvent>) -> anyhow::Result<Self> {
+        Ok(Self {
+            messages: Vec::new(),
+            connection: ConnectionState::Connected(1),
+            disconnected: false,
+            failing_topic: None,
+        })
+    }
+}
@@ -1,15 +1,54 @@
 use bytes::Bytes;
-use std::sync::mpsc::Sender;
+use serde::Deserialize;
+use std::{
+    sync::mpsc::Sender,
+    thread,
+    time::{Duration, Instant},
+};
 
 use super::mqtt_config::MqttConfig;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u8")]
 pub enum QoS {
+    #[default]
     AtMostOnce,
     AtLeastOnce,
     ExactlyOnce,
 }
 
+impl TryFrom<u8> for QoS {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::AtMostOnce),
+            1 => Ok(Self::AtLeastOnce),
+            2 => Ok(Self::ExactlyOnce),
+            _ => Err(format!("invalid QoS {value}, expected 0, 1 or 2")),
+        }
+    }
+}
+
+/// MQTT v5 properties of a published message, ignored by MQTT 3.1.1 clients
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PublishProperties {
+    /// MIME type of the payload, e.g. `application/json`
+    pub content_type: Option<String>,
+    /// Seconds after which the broker discards the message, including retained copies
+    pub message_expiry: Option<u32>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+/// State of the connection to the broker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Not connected yet or waiting to reconnect
+    Disconnected,
+    /// Connected, counts the accepted connections so reconnects can be detected
+    Connected(u64),
+}
+
 pub struct PublishEvent {
     pub topic: String,
     pub qos: QoS,
@@ -19,7 +58,7 @@ pub struct PublishEvent {
 
 // TODO: add an implementation of the MqttWrapper for testing
 // TODO: should this be renamed to MqttImplementation?
-pub trait MqttWrapper {
+pub trait MqttWrapper: Sized {
     // This trait provides an interface that the decouples library code from an
     // implementation of the MQTT client. On library calling code, one needs to
     // wrap the MQTT implementation, i.e. the client, in a new type that in
@@ -32,5 +71,48 @@ pub trait MqttWrapper {
         S: Clone + Into<String>,
         V: Clone + Into<Vec<u8>>;
 
-    fn new(config: &MqttConfig, pub_tx: Sender<PublishEvent>) -> Self;
+    /// Publishes with MQTT v5 properties. Clients without v5 support publish without them.
+    fn publish_with_properties<S, V>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+        _properties: PublishProperties,
+    ) -> anyhow::Result<()>
+    where
+        S: Clone + Into<String>,
+        V: Clone + Into<Vec<u8>>,
+    {
+        self.publish(topic, qos, retain, payload)
+    }
+
+    /// Clients that do not track their connection are always connected.
+    fn connection_state(&self) -> ConnectionState {
+        ConnectionState::Connected(1)
+    }
+
+    /// Whether the client is currently connected to the broker
+    fn is_connected(&self) -> bool {
+        matches!(self.connection_state(), ConnectionState::Connected(_))
+    }
+
+    /// Waits up to `timeout` for a connection to the broker, returns whether it is connected
+    fn wait_until_connected(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.is_connected() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        true
+    }
+
+    /// Sends all pending messages and closes the connection. Gives up on pending messages if the
+    /// broker is unreachable.
+    fn disconnect(&mut self) -> anyhow::Result<()>;
+
+    /// Creates the client, failing on invalid settings like unreadable certificates.
+    fn new(config: &MqttConfig, pub_tx: Sender<PublishEvent>) -> anyhow::Result<Self>;
 }
@@ -0,0 +1,221 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+    time::Duration,
+};
+
+use anyhow::bail;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    mqtt_config::MqttConfig,
+    mqtt_wrapper::{MqttWrapper, QoS},
+};
+
+/// Time to wait for the broker before purging is given up
+const PURGE_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `RetainedTopics` remembers which retained topics a target published for each DTU.
+///
+/// Topics that are no longer published (e.g. a port disappeared or an alias changed)
+/// are reported as stale so the target can clear them on the broker. If a path is
+/// given, the state is persisted to survive restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RetainedTopics {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Maps the DTU serial to the topics published for it
+    topics: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl RetainedTopics {
+    /// Loads the topics from `path`, warning that obsolete topics are only removed until the
+    /// next restart if there is no path
+    pub fn load_or_warn(path: Option<&Path>) -> Self {
+        if path.is_none() {
+            warn!("No state_file configured, obsolete retained topics are forgotten on restart and can't be purged");
+        }
+        Self::load(path)
+    }
+
+    pub fn load(path: Option<&Path>) -> Self {
+        let mut retained_topics: Self = path
+            .filter(|path| path.exists())
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(contents) => serde_json::from_str(&contents)
+                    .map_err(|e| warn!("Ignoring invalid state file {path:?}: {e}"))
+                    .ok(),
+                Err(e) => {
+                    warn!("Failed to read state file {path:?}: {e}");
+                    None
+                }
+            })
+            .unwrap_or_default();
+        retained_topics.path = path.map(Path::to_path_buf);
+        retained_topics
+    }
+
+    /// Stores the topics currently published for `dtu_sn` and returns the ones
+    /// that were published before but are obsolete now.
+    pub fn update(&mut self, dtu_sn: &str, topics: BTreeSet<String>) -> Vec<String> {
+        let previous = self.topics.insert(dtu_sn.to_string(), topics);
+        let current = &self.topics[dtu_sn];
+        if previous.as_ref() == Some(current) {
+            return Vec::new();
+        }
+
+        let stale = previous
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|topic| !current.contains(topic))
+            .collect();
+        self.save();
+        stale
+    }
+
+    /// Publishes an empty retained message to every remembered topic and closes the connection.
+    ///
+    /// A topic is only forgotten once its message has been sent, the state file is left unchanged
+    /// if the pending messages could not be sent before disconnecting.
+    pub(crate) fn purge(&mut self, client: &mut impl MqttWrapper, qos: QoS) -> anyhow::Result<()> {
+        if self.path.is_none() {
+            bail!("purging requires a state_file with the published topics");
+        }
+        if !client.wait_until_connected(PURGE_CONNECT_TIMEOUT) {
+            // the event loop gives up on the disconnect as well
+            let _ = client.disconnect();
+            bail!("MQTT broker is unreachable, no topics were removed");
+        }
+        let topics: Vec<_> = self
+            .topics
+            .iter()
+            .flat_map(|(dtu_sn, topics)| topics.iter().map(|topic| (dtu_sn.clone(), topic.clone())))
+            .collect();
+        info!("Removing {} retained topics", topics.len());
+        let mut published = Ok(());
+        for (dtu_sn, topic) in topics {
+            debug!("Removing obsolete topic {topic}");
+            if let Err(e) = client.publish(topic.clone(), qos, true, "") {
+                published = Err(e.context(format!("failed to remove {topic}")));
+                break;
+            }
+            if let Some(topics) = self.topics.get_mut(&dtu_sn) {
+                topics.remove(&topic);
+                if topics.is_empty() {
+                    self.topics.remove(&dtu_sn);
+                }
+            }
+        }
+        // the messages are only sent once the client disconnects
+        let disconnected = client.disconnect();
+        if disconnected.is_ok() {
+            self.save();
+        }
+        published.and(disconnected)
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        debug!("Saving published topics to {path:?}");
+        let result = serde_json::to_string_pretty(self)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(fs::write(path, contents)?));
+        if let Err(e) = result {
+            warn!("Failed to write state file {path:?}: {e}");
+        }
+    }
+}
+
+/// Removes the retained topics listed in the state file of a target that is no longer configured,
+/// using the broker of `config`.
+pub fn purge_state_file<MQTT: MqttWrapper>(
+    config: &MqttConfig,
+    state_file: &Path,
+) -> anyhow::Result<()> {
+    if !state_file.exists() {
+        bail!("state file {state_file:?} does not exist");
+    }
+    let mut retained_topics = RetainedTopics::load(Some(state_file));
+    let (tx, _rx) = channel();
+    let mut client = MQTT::new(config, tx)?;
+    retained_topics.purge(&mut client, config.qos)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{purge_state_file, RetainedTopics};
+    use crate::targets::mqtt::{
+        mqtt_config::MqttConfig,
+        mqtt_stub::MqttStub,
+        mqtt_wrapper::{ConnectionState, MqttWrapper, QoS},
+    };
+
+    #[test]
+    fn test_update_returns_stale_topics() {
+        let mut retained_topics = RetainedTopics::load(None);
+        let stale = retained_topics.update("123", ["a".into(), "b".into()].into());
+        assert!(stale.is_empty());
+
+        let stale = retained_topics.update("456", ["c".into()].into());
+        assert!(stale.is_empty());
+
+        let stale = retained_topics.update("123", ["b".into()].into());
+        assert_eq!(stale, vec!["a".to_string()]);
+        assert_eq!(retained_topics.topics["123"], ["b".into()].into());
+        assert_eq!(retained_topics.topics["456"], ["c".into()].into());
+    }
+
+    #[test]
+    fn test_purge() {
+        let state_file =
+            std::env::temp_dir().join(format!("retained_topics_{}.json", std::process::id()));
+        let mut retained_topics = RetainedTopics::load(Some(&state_file));
+        retained_topics.update("123", ["a".into(), "b".into()].into());
+
+        // nothing is forgotten while the broker is unreachable
+        let mut client =
+            MqttStub::new(&MqttConfig::default(), std::sync::mpsc::channel().0).unwrap();
+        client.connection = ConnectionState::Disconnected;
+        assert!(retained_topics.purge(&mut client, QoS::AtMostOnce).is_err());
+        assert!(client.messages.is_empty());
+        assert!(fs::read_to_string(&state_file).unwrap().contains("\"a\""));
+
+        // topics that could not be cleared are kept for the next purge
+        let mut client =
+            MqttStub::new(&MqttConfig::default(), std::sync::mpsc::channel().0).unwrap();
+        client.failing_topic = Some("b".into());
+        assert!(retained_topics.purge(&mut client, QoS::AtMostOnce).is_err());
+        assert_eq!(client.messages.len(), 1);
+        assert!(client.disconnected);
+        let remaining = RetainedTopics::load(Some(&state_file));
+        assert_eq!(
+            remaining.topics,
+            [("123".into(), ["b".into()].into())].into()
+        );
+
+        // state files of removed targets can be purged as well
+        purge_state_file::<MqttStub>(&MqttConfig::default(), &state_file).unwrap();
+        let remaining = RetainedTopics::load(Some(&state_file));
+        assert!(remaining.topics.is_empty());
+        assert!(purge_state_file::<MqttStub>(
+            &MqttConfig::default(),
+            &state_file.with_extension("missing")
+        )
+        .is_err());
+
+        // without a state file nothing is known to purge
+        let mut client =
+            MqttStub::new(&MqttConfig::default(), std::sync::mpsc::channel().0).unwrap();
+        assert!(RetainedTopics::load(None)
+            .purge(&mut client, QoS::AtMostOnce)
+            .is_err());
+        fs::remove_file(state_file).unwrap();
+    }
+}
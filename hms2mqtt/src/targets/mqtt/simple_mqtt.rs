@@ -1,10 +1,11 @@
 use crate::{
     protos::hoymiles::RealData::HMSStateResponse,
     targets::{
+        change_detector::ChangeDetector,
         metric_publisher::MetricPublisher,
         mqtt::{
             mqtt_config::MqttConfig,
-            mqtt_wrapper::{MqttWrapper, QoS},
+            mqtt_wrapper::{MqttWrapper, PublishProperties},
         },
     },
 };
@@ -13,81 +14,292 @@ use chrono::prelude::DateTime;
 use chrono::Local;
 use log::{debug, warn};
 use std::{
+    collections::HashSet,
     sync::mpsc::channel,
     time::{Duration, UNIX_EPOCH},
 };
 
 pub struct SimpleMqtt<MQTT: MqttWrapper> {
     client: MQTT,
+    config: MqttConfig,
+    seen_dtus: HashSet<String>,
+    change_detector: ChangeDetector,
 }
 
 impl<MQTT: MqttWrapper> SimpleMqtt<MQTT> {
-    pub fn new(config: &MqttConfig) -> Self {
+    pub fn new(config: &MqttConfig) -> anyhow::Result<Self> {
         let (tx, _rx) = channel();
-        let client = MQTT::new(config, tx);
-        Self { client }
+        let client = MQTT::new(config, tx)?;
+        Ok(Self {
+            client,
+            config: config.clone(),
+            seen_dtus: HashSet::new(),
+            change_detector: ChangeDetector::new(config.publish_on_change.clone()),
+        })
+    }
+
+    fn get_prefix(&mut self, hms_state: &HMSStateResponse) -> String {
+        if self.config.per_dtu_topics {
+            let serial = hms_state.get_alias(&self.config.serial_aliases);
+            return format!("{}/{}", self.config.base_topic, serial);
+        }
+
+        if self.seen_dtus.insert(hms_state.dtu_sn.clone()) && self.seen_dtus.len() > 1 {
+            warn!("Multiple DTUs publish to the same simple MQTT topics, consider enabling per_dtu_topics");
+        }
+        self.config.base_topic.clone()
     }
 }
 
 impl<MQTT: MqttWrapper> MetricPublisher for SimpleMqtt<MQTT> {
+    fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
     fn publish(&mut self, hms_state: &HMSStateResponse) {
+        self.change_detector
+            .reset_on_reconnect(self.client.connection_state());
+
         debug!("{hms_state}");
 
-        let d = UNIX_EPOCH + Duration::from_secs(hms_state.time as u64);
+        let prefix = self.get_prefix(hms_state);
+        let alias = hms_state.get_alias(&self.config.serial_aliases);
+        hms_state
+            .get_simple_topics(&prefix)
+            .into_iter()
+            .filter(|(topic, payload, _)| {
+                let key = topic[prefix.len()..].trim_start_matches('/');
+                if !self
+                    .config
+                    .filter
+                    .is_included(&hms_state.dtu_sn, alias, key)
+                {
+                    return false;
+                }
+                // the inverter local time is no number and changes with every reply anyway
+                payload.parse().map_or(true, |value| {
+                    self.change_detector
+                        .is_changed(&hms_state.dtu_sn, [(key, value)])
+                })
+            })
+            .for_each(|(topic, payload, unit)| {
+                let key = topic[prefix.len()..].trim_start_matches('/');
+                let (qos, retain) = self.config.get_publish_options(key);
+                let properties = PublishProperties {
+                    content_type: Some("text/plain".into()),
+                    message_expiry: self.config.get_key_message_expiry(key, unit),
+                    user_properties: [("serial".into(), hms_state.dtu_sn.clone())]
+                        .into_iter()
+                        .chain(unit.map(|unit| ("unit".into(), unit.into())))
+                        .collect(),
+                };
+                if let Err(e) = self
+                    .client
+                    .publish_with_properties(topic, qos, retain, payload, properties)
+                {
+                    warn!("mqtt error: {e:?}")
+                }
+            });
+    }
+}
+
+impl HMSStateResponse {
+    /// Returns the topics, payloads and units of the flat layout of the simple MQTT target.
+    ///
+    /// A single inverter with two ports results in the legacy topics, e.g. `pv_grid_voltage`
+    /// and `pv_port2_power`. Multiple inverters are numbered, e.g. `pv_inv2_grid_voltage`.
+    fn get_simple_topics(&self, prefix: &str) -> Vec<(String, String, Option<&'static str>)> {
+        let d = UNIX_EPOCH + Duration::from_secs(self.time as u64);
         let datetime = DateTime::<Local>::from(d);
         let inverter_local_time = datetime.format("%Y-%m-%d %H:%M:%S.%f").to_string();
 
-        let pv_current_power = hms_state.pv_current_power as f32 / 10.;
-        let pv_daily_yield = hms_state.pv_daily_yield;
-        let pv_grid_voltage = hms_state.inverter_state[0].grid_voltage as f32 / 10.;
-        let pv_grid_freq = hms_state.inverter_state[0].grid_freq as f32 / 100.;
-        let pv_inv_temperature = hms_state.inverter_state[0].temperature as f32 / 10.;
-        let pv_port1_voltage = hms_state.port_state[0].pv_vol as f32 / 10.;
-        let pv_port1_curr = hms_state.port_state[0].pv_cur as f32 / 100.;
-        let pv_port1_power = hms_state.port_state[0].pv_power as f32 / 10.;
-        let pv_port1_energy = hms_state.port_state[0].pv_energy_total as f32;
-        let pv_port1_daily_yield = hms_state.port_state[0].pv_daily_yield as f32;
-        let pv_port2_voltage = hms_state.port_state[1].pv_vol as f32 / 10.;
-        let pv_port2_curr = hms_state.port_state[1].pv_cur as f32 / 100.;
-        let pv_port2_power = hms_state.port_state[1].pv_power as f32 / 10.;
-        let pv_port2_energy = hms_state.port_state[1].pv_energy_total as f32;
-        let pv_port2_daily_yield = hms_state.port_state[1].pv_daily_yield as f32;
-
-        // TODO: this section bears a lot of repetition. Investigate if there's a more idiomatic way to get the same result, perhaps using a macro
-        let topic_payload_pairs = [
-            ("hms800wt2/inverter_local_time", inverter_local_time),
-            ("hms800wt2/pv_current_power", pv_current_power.to_string()),
-            ("hms800wt2/pv_daily_yield", pv_daily_yield.to_string()),
-            ("hms800wt2/pv_grid_voltage", pv_grid_voltage.to_string()),
-            ("hms800wt2/pv_grid_freq", pv_grid_freq.to_string()),
+        let pv_current_power = self.pv_current_power as f32 / 10.;
+        let pv_daily_yield = self.pv_daily_yield;
+
+        let mut topic_payload_pairs = vec![
             (
-                "hms800wt2/pv_inv_temperature",
-                pv_inv_temperature.to_string(),
+                format!("{prefix}/inverter_local_time"),
+                inverter_local_time,
+                None,
             ),
-            ("hms800wt2/pv_port1_voltage", pv_port1_voltage.to_string()),
-            ("hms800wt2/pv_port1_curr", pv_port1_curr.to_string()),
-            ("hms800wt2/pv_port1_power", pv_port1_power.to_string()),
-            ("hms800wt2/pv_port1_energy", pv_port1_energy.to_string()),
             (
-                "hms800wt2/pv_port1_daily_yield",
-                pv_port1_daily_yield.to_string(),
+                format!("{prefix}/pv_current_power"),
+                pv_current_power.to_string(),
+                Some("W"),
             ),
-            ("hms800wt2/pv_port2_voltage", pv_port2_voltage.to_string()),
-            ("hms800wt2/pv_port2_curr", pv_port2_curr.to_string()),
-            ("hms800wt2/pv_port2_power", pv_port2_power.to_string()),
-            ("hms800wt2/pv_port2_energy", pv_port2_energy.to_string()),
             (
-                "hms800wt2/pv_port2_daily_yield",
-                pv_port2_daily_yield.to_string(),
+                format!("{prefix}/pv_daily_yield"),
+                pv_daily_yield.to_string(),
+                Some("Wh"),
             ),
         ];
 
+        let single_inverter = self.inverter_state.len() == 1;
+        for (idx, inverter_state) in self.inverter_state.iter().enumerate() {
+            let pv_grid_voltage = inverter_state.grid_voltage as f32 / 10.;
+            let pv_grid_freq = inverter_state.grid_freq as f32 / 100.;
+            let pv_inv_temperature = inverter_state.temperature as f32 / 10.;
+            let numbered = format!("pv_inv{}", idx + 1);
+            let (inverter, temperature) = if single_inverter {
+                ("pv", "pv_inv")
+            } else {
+                (numbered.as_str(), numbered.as_str())
+            };
+
+            topic_payload_pairs.extend([
+                (
+                    format!("{prefix}/{inverter}_grid_voltage"),
+                    pv_grid_voltage.to_string(),
+                    Some("V"),
+                ),
+                (
+                    format!("{prefix}/{inverter}_grid_freq"),
+                    pv_grid_freq.to_string(),
+                    Some("Hz"),
+                ),
+                (
+                    format!("{prefix}/{temperature}_temperature"),
+                    pv_inv_temperature.to_string(),
+                    Some("°C"),
+                ),
+            ]);
+        }
+
+        for (idx, port_state) in self.port_state.iter().enumerate() {
+            let pv_port_voltage = port_state.pv_vol as f32 / 10.;
+            let pv_port_curr = port_state.pv_cur as f32 / 100.;
+            let pv_port_power = port_state.pv_power as f32 / 10.;
+            let pv_port_energy = port_state.pv_energy_total as f32;
+            let pv_port_daily_yield = port_state.pv_daily_yield as f32;
+            let port = format!("{prefix}/pv_port{}", idx + 1);
+
+            topic_payload_pairs.extend([
+                (
+                    format!("{port}_voltage"),
+                    pv_port_voltage.to_string(),
+                    Some("V"),
+                ),
+                (format!("{port}_curr"), pv_port_curr.to_string(), Some("A")),
+                (
+                    format!("{port}_power"),
+                    pv_port_power.to_string(),
+                    Some("W"),
+                ),
+                (
+                    format!("{port}_energy"),
+                    pv_port_energy.to_string(),
+                    Some("Wh"),
+                ),
+                (
+                    format!("{port}_daily_yield"),
+                    pv_port_daily_yield.to_string(),
+                    Some("Wh"),
+                ),
+            ]);
+        }
         topic_payload_pairs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SimpleMqtt;
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, InverterState, PortState},
+        targets::{
+            metric_publisher::MetricPublisher,
+            mqtt::{mqtt_config::MqttConfig, mqtt_stub::MqttStub},
+        },
+    };
+
+    fn topics(state: &HMSStateResponse) -> Vec<String> {
+        state
+            .get_simple_topics("hms800wt2")
             .into_iter()
-            .for_each(|(topic, payload)| {
-                if let Err(e) = self.client.publish(topic, QoS::AtMostOnce, true, payload) {
-                    warn!("mqtt error: {e:?}")
-                }
-            });
+            .map(|(topic, _, _)| topic)
+            .collect()
+    }
+
+    #[test]
+    fn test_legacy_topics() {
+        let state = HMSStateResponse {
+            inverter_state: vec![InverterState::default()],
+            port_state: vec![PortState::default(), PortState::default()],
+            ..Default::default()
+        };
+        let topics = topics(&state);
+
+        assert_eq!(topics.len(), 16);
+        for topic in [
+            "hms800wt2/inverter_local_time",
+            "hms800wt2/pv_grid_voltage",
+            "hms800wt2/pv_inv_temperature",
+            "hms800wt2/pv_port1_voltage",
+            "hms800wt2/pv_port2_daily_yield",
+        ] {
+            assert!(topics.contains(&topic.to_string()), "missing {topic}");
+        }
+    }
+
+    #[test]
+    fn test_any_number_of_ports_and_inverters() {
+        assert_eq!(topics(&HMSStateResponse::default()).len(), 3);
+
+        let state = HMSStateResponse {
+            inverter_state: vec![InverterState::default(), InverterState::default()],
+            port_state: vec![PortState::default(); 4],
+            ..Default::default()
+        };
+        let topics = topics(&state);
+
+        assert_eq!(topics.len(), 3 + 2 * 3 + 4 * 5);
+        assert!(topics.contains(&"hms800wt2/pv_inv2_temperature".to_string()));
+        assert!(topics.contains(&"hms800wt2/pv_port4_power".to_string()));
+    }
+
+    #[test]
+    fn test_v5_properties() {
+        let config: MqttConfig = serde_yaml::from_str(
+            r#"
+            host: "::1"
+            protocol_version: v5
+            message_expiry: 90
+            publish_overrides:
+              - metrics: "inverter_local_time"
+                message_expiry: 3600
+            "#,
+        )
+        .unwrap();
+        let mut simple_mqtt = SimpleMqtt::<MqttStub>::new(&config).unwrap();
+        simple_mqtt.publish(&HMSStateResponse {
+            dtu_sn: "123".into(),
+            inverter_state: vec![InverterState::default()],
+            port_state: vec![PortState::default()],
+            ..Default::default()
+        });
+
+        let messages = &simple_mqtt.client.messages;
+        let message = |topic: &str| {
+            messages
+                .iter()
+                .find(|message| message.topic == format!("hms800wt2/{topic}"))
+                .unwrap()
+        };
+        let power = &message("pv_port1_power").properties;
+        assert_eq!(power.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(power.message_expiry, Some(90));
+        assert_eq!(
+            power.user_properties,
+            [
+                ("serial".to_string(), "123".to_string()),
+                ("unit".to_string(), "W".to_string())
+            ]
+        );
+        let energy = &message("pv_port1_energy").properties;
+        assert_eq!(energy.message_expiry, None);
+        assert_eq!(energy.user_properties[1], ("unit".into(), "Wh".into()));
+        let time = &message("inverter_local_time").properties;
+        assert_eq!(time.message_expiry, Some(3600));
+        assert_eq!(time.user_properties.len(), 1);
     }
 }
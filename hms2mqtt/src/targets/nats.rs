@@ -0,0 +1,379 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    targets::{
+        metric_filter::MetricFilter, metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias,
+    },
+};
+
+fn default_port() -> u16 {
+    4222
+}
+
+fn default_subject_prefix() -> String {
+    "hms".into()
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NatsConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Token authentication, instead of username and password
+    pub token: Option<String>,
+    /// Snapshots are published to `<prefix>.dtu.<serial or alias>.state`
+    #[serde(default = "default_subject_prefix")]
+    pub subject_prefix: String,
+    /// Additionally publish every inverter and port, e.g. to `<prefix>.dtu.<serial>.port.1.state`
+    #[serde(default)]
+    pub per_component: bool,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the published values
+    #[serde(default)]
+    pub filter: MetricFilter,
+    /// Seconds before connecting or writing is aborted
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// Replaces characters with a special meaning in subjects
+fn sanitize(token: &str) -> String {
+    token
+        .chars()
+        .map(|c| match c {
+            '.' | '*' | '>' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Returns the subject of an MQTT topic, e.g. `hms.dtu.roof.state` for `hms/dtu/roof/state`
+fn get_subject(topic: &str) -> String {
+    topic.split('/').map(sanitize).collect::<Vec<_>>().join(".")
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Received bytes not processed yet
+    received: Vec<u8>,
+}
+
+impl Connection {
+    /// Returns the next complete line received from the server, if any
+    fn next_line(&mut self) -> Option<String> {
+        let end = self
+            .received
+            .windows(2)
+            .position(|window| window == b"\r\n")?;
+        let line = String::from_utf8_lossy(&self.received[..end]).into_owned();
+        self.received.drain(..end + 2);
+        Some(line)
+    }
+
+    /// Waits for the next line from the server
+    fn read_line(&mut self) -> anyhow::Result<String> {
+        loop {
+            if let Some(line) = self.next_line() {
+                return Ok(line);
+            }
+            self.receive()?;
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 4096];
+        match self.stream.read(&mut buffer)? {
+            0 => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed by the server",
+            )),
+            length => {
+                self.received.extend_from_slice(&buffer[..length]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Answers pings of the server, which closes connections that don't
+    fn handle_server_messages(&mut self) -> anyhow::Result<()> {
+        self.stream.set_nonblocking(true)?;
+        let result = loop {
+            match self.receive() {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result?;
+
+        while let Some(line) = self.next_line() {
+            match line.as_str() {
+                "PING" => self.stream.write_all(b"PONG\r\n")?,
+                line if line.starts_with("-ERR") => warn!("NATS server reported {line}"),
+                line => debug!("Ignoring NATS message {line}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `Nats` publishes the JSON documents of the MQTT target to NATS subjects.
+///
+/// The connection is established lazily and re-established after failures with an increasing
+/// delay, so an unreachable server does not delay every poll by the connect timeout.
+pub struct Nats {
+    config: NatsConfig,
+    connection: Option<Connection>,
+    reconnect_delay: Duration,
+    next_connect: Instant,
+}
+
+impl Nats {
+    pub fn new(config: &NatsConfig) -> anyhow::Result<Self> {
+        if config.token.is_some() && config.username.is_some() {
+            bail!("token and username are mutually exclusive");
+        }
+        Ok(Self {
+            config: config.clone(),
+            connection: None,
+            reconnect_delay: MIN_RECONNECT_DELAY,
+            next_connect: Instant::now(),
+        })
+    }
+
+    fn ensure_connected(&mut self) {
+        if self.connection.is_some() || Instant::now() < self.next_connect {
+            return;
+        }
+        match self.connect() {
+            Ok(connection) => {
+                self.connection = Some(connection);
+                self.reconnect_delay = MIN_RECONNECT_DELAY;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to connect to NATS, retrying in {:?}: {e:#}",
+                    self.reconnect_delay
+                );
+                self.next_connect = Instant::now() + self.reconnect_delay;
+                self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+
+    fn connect(&self) -> anyhow::Result<Connection> {
+        let address = format!("{}:{}", self.config.host, self.config.port);
+        let timeout = Duration::from_secs(self.config.timeout);
+        let socket_address = address
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("could not resolve {address}"))?;
+        let stream = TcpStream::connect_timeout(&socket_address, timeout)
+            .with_context(|| format!("could not connect to {address}"))?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut connection = Connection {
+            stream,
+            received: Vec::new(),
+        };
+
+        let info = connection.read_line()?;
+        if !info.starts_with("INFO ") {
+            bail!("unexpected greeting from {address}: {info}");
+        }
+        let connect = json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "hms-mqtt-publish",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "user": self.config.username,
+            "pass": self.config.password,
+            "auth_token": self.config.token,
+        });
+        write!(connection.stream, "CONNECT {connect}\r\nPING\r\n")?;
+        // the server answers the ping after processing the connect, or reports an error
+        let reply = connection.read_line()?;
+        if reply != "PONG" {
+            bail!("{address} refused the connection: {reply}");
+        }
+        info!("Connected to NATS at {address}");
+        Ok(connection)
+    }
+
+    fn send(&mut self, messages: &[(String, String)]) -> anyhow::Result<()> {
+        let mut buffer = Vec::new();
+        for (subject, payload) in messages {
+            write!(buffer, "PUB {subject} {}\r\n{payload}\r\n", payload.len())?;
+        }
+        // the connection may have been closed since the last snapshot, so reconnect once
+        for _ in 0..2 {
+            self.ensure_connected();
+            let Some(connection) = self.connection.as_mut() else {
+                break;
+            };
+            let result = connection
+                .handle_server_messages()
+                .and_then(|()| Ok(connection.stream.write_all(&buffer)?));
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!("Lost connection to NATS: {e:#}");
+                    self.connection = None;
+                }
+            }
+        }
+        bail!("NATS is unreachable")
+    }
+}
+
+impl MetricPublisher for Nats {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        let alias = hms_state.get_alias(&self.config.serial_aliases);
+        let metrics: Vec<_> = hms_state
+            .get_metrics()
+            .into_iter()
+            .filter(|metric| {
+                self.config
+                    .filter
+                    .is_included(&hms_state.dtu_sn, alias, &metric.path())
+            })
+            .collect();
+        let base_topic = hms_state.get_base_topic(
+            Some(self.config.subject_prefix.as_str()).filter(|prefix| !prefix.is_empty()),
+            &self.config.serial_aliases,
+        );
+        let messages: Vec<_> = hms_state
+            .get_json_documents(&base_topic, &metrics, self.config.per_component)
+            .into_iter()
+            .map(|(topic, document)| (get_subject(&topic), document.to_string()))
+            .collect();
+        if let Err(e) = self.send(&messages) {
+            warn!("Failed to publish to NATS: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::{Nats, NatsConfig, MIN_RECONNECT_DELAY};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, PortState},
+        targets::metric_publisher::MetricPublisher,
+    };
+
+    #[test]
+    fn test_publish_snapshots() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config: NatsConfig = serde_yaml::from_str(&format!(
+            r#"
+            host: "127.0.0.1"
+            port: {}
+            token: "secret"
+            per_component: true
+            serial_aliases:
+              - serial: "123"
+                alias: "my roof"
+            filter:
+              include: ["current_power", "port/*/power"]
+            "#,
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(b"INFO {\"server_id\":\"test\"}\r\n")
+                .unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut lines = Vec::new();
+            for _ in 0..6 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "PING\r\n" {
+                    stream.write_all(b"PONG\r\n").unwrap();
+                }
+                lines.push(line.trim_end().to_string());
+            }
+            lines
+        });
+
+        let mut nats = Nats::new(&config).unwrap();
+        nats.publish(&HMSStateResponse {
+            dtu_sn: "123".into(),
+            pv_current_power: 1234,
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_power: 617,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+
+        let lines = server.join().unwrap();
+        assert!(lines[0].starts_with("CONNECT {"));
+        assert!(lines[0].contains(r#""auth_token":"secret""#));
+        assert_eq!(lines[1], "PING");
+        assert!(lines[2].starts_with("PUB hms.dtu.my_roof.port.1.state "));
+        let document: serde_json::Value = serde_json::from_str(&lines[3]).unwrap();
+        assert_eq!(document["values"]["power"]["value"], 61.7);
+        assert!(lines[4].starts_with("PUB hms.dtu.my_roof.state "));
+        let length: usize = lines[4].rsplit(' ').next().unwrap().parse().unwrap();
+        assert_eq!(lines[5].len(), length);
+    }
+
+    #[test]
+    fn test_reconnect_delay() {
+        // a port nobody listens on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config: NatsConfig =
+            serde_yaml::from_str(&format!("host: '127.0.0.1'\nport: {port}")).unwrap();
+        let mut nats = Nats::new(&config).unwrap();
+        let snapshot = HMSStateResponse {
+            dtu_sn: "123".into(),
+            ..Default::default()
+        };
+        nats.publish(&snapshot);
+        assert_eq!(nats.reconnect_delay, MIN_RECONNECT_DELAY * 2);
+        // the next attempt waits for the delay instead of connecting on every snapshot
+        nats.publish(&snapshot);
+        assert_eq!(nats.reconnect_delay, MIN_RECONNECT_DELAY * 2);
+        assert!(nats.connection.is_none());
+    }
+}
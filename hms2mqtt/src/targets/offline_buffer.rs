@@ -0,0 +1,341 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use log::{debug, info, warn};
+use protobuf::Message;
+use serde::Deserialize;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse, targets::metric_publisher::MetricPublisher,
+};
+
+fn default_max_snapshots() -> usize {
+    // one day with the default update interval
+    2880
+}
+
+fn default_replay_batch_size() -> usize {
+    100
+}
+
+/// Snapshot discarded when the buffer is full
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    #[default]
+    DropOldest,
+    DropNewest,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OfflineBufferConfig {
+    /// File the snapshots are stored in, has to be different for every target
+    pub file: PathBuf,
+    #[serde(default = "default_max_snapshots")]
+    pub max_snapshots: usize,
+    #[serde(default)]
+    pub drop_policy: DropPolicy,
+    /// Snapshots replayed per poll, so a long backlog doesn't delay the polling
+    #[serde(default = "default_replay_batch_size")]
+    pub replay_batch_size: usize,
+}
+
+/// `OfflineBuffer` stores the snapshots of a target while it is disconnected and
+/// replays them in order once it is connected again.
+///
+/// Snapshots keep the inverter timestamp, so replayed values carry their original time.
+/// The buffer is persisted to survive restarts during an outage. New snapshots are appended to
+/// the file, which is only rewritten after a replay or once it holds twice `max_snapshots`.
+pub struct OfflineBuffer<P: MetricPublisher> {
+    target: P,
+    config: OfflineBufferConfig,
+    snapshots: VecDeque<HMSStateResponse>,
+    /// Snapshots in the file, including the dropped ones since the last rewrite
+    file_snapshots: usize,
+}
+
+impl<P: MetricPublisher> OfflineBuffer<P> {
+    pub fn new(target: P, config: OfflineBufferConfig) -> Self {
+        let snapshots = load_snapshots(&config);
+        if !snapshots.is_empty() {
+            info!(
+                "Loaded {} buffered snapshots from {:?}",
+                snapshots.len(),
+                config.file
+            );
+        }
+        Self {
+            target,
+            config,
+            file_snapshots: snapshots.len(),
+            snapshots,
+        }
+    }
+
+    fn push(&mut self, hms_state: &HMSStateResponse) {
+        if self.snapshots.len() >= self.config.max_snapshots {
+            match self.config.drop_policy {
+                DropPolicy::DropOldest => {
+                    self.snapshots.pop_front();
+                }
+                DropPolicy::DropNewest => {
+                    warn!("Offline buffer is full, dropping snapshot");
+                    return;
+                }
+            }
+        }
+        self.snapshots.push_back(hms_state.clone());
+        // loading keeps the newest snapshots, so dropped ones may stay in the file for a while
+        if self.file_snapshots >= 2 * self.config.max_snapshots {
+            self.save();
+        } else {
+            self.append(hms_state);
+        }
+    }
+
+    fn append(&mut self, hms_state: &HMSStateResponse) {
+        let mut contents = Vec::new();
+        encode(hms_state, &mut contents);
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.file)
+            .and_then(|mut file| file.write_all(&contents));
+        match result {
+            Ok(()) => self.file_snapshots += 1,
+            Err(e) => warn!("Failed to write offline buffer {:?}: {e}", self.config.file),
+        }
+    }
+
+    /// Publishes up to `replay_batch_size` buffered snapshots while the target is connected
+    fn replay(&mut self) {
+        if self.snapshots.is_empty() {
+            return;
+        }
+        info!(
+            "Replaying up to {} of {} buffered snapshots",
+            self.config.replay_batch_size,
+            self.snapshots.len()
+        );
+        let mut replayed = 0;
+        while replayed < self.config.replay_batch_size && self.target.is_connected() {
+            let Some(hms_state) = self.snapshots.pop_front() else {
+                break;
+            };
+            self.target.publish(&hms_state);
+            replayed += 1;
+        }
+        if replayed > 0 {
+            self.save();
+        }
+    }
+
+    fn save(&mut self) {
+        debug!(
+            "Saving {} buffered snapshots to {:?}",
+            self.snapshots.len(),
+            self.config.file
+        );
+        // snapshots are stored as protobuf messages with a length prefix
+        let mut contents = Vec::new();
+        for hms_state in &self.snapshots {
+            encode(hms_state, &mut contents);
+        }
+        // write a temporary file first to not lose the buffer on a crash
+        let temporary_file = self.config.file.with_extension("tmp");
+        let result = fs::write(&temporary_file, contents)
+            .and_then(|_| fs::rename(&temporary_file, &self.config.file));
+        match result {
+            Ok(()) => self.file_snapshots = self.snapshots.len(),
+            Err(e) => warn!("Failed to write offline buffer {:?}: {e}", self.config.file),
+        }
+    }
+}
+
+/// Appends the snapshot as protobuf message with a length prefix
+fn encode(hms_state: &HMSStateResponse, contents: &mut Vec<u8>) {
+    match hms_state.write_to_bytes() {
+        Ok(bytes) => {
+            contents.extend((bytes.len() as u32).to_le_bytes());
+            contents.extend(bytes);
+        }
+        Err(e) => warn!("Failed to serialize snapshot: {e}"),
+    }
+}
+
+fn load_snapshots(config: &OfflineBufferConfig) -> VecDeque<HMSStateResponse> {
+    let path = &config.file;
+    if !path.exists() {
+        return VecDeque::new();
+    }
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            warn!("Failed to read offline buffer {path:?}: {e}");
+            return VecDeque::new();
+        }
+    };
+
+    let mut snapshots = VecDeque::new();
+    let mut rest = contents.as_slice();
+    while let Some((length, tail)) = rest.split_first_chunk::<4>() {
+        let length = u32::from_le_bytes(*length) as usize;
+        let Some(message) = tail.get(..length) else {
+            warn!("Ignoring truncated snapshot in offline buffer {path:?}");
+            break;
+        };
+        match HMSStateResponse::parse_from_bytes(message) {
+            Ok(hms_state) => snapshots.push_back(hms_state),
+            Err(e) => warn!("Ignoring invalid snapshot in offline buffer {path:?}: {e}"),
+        }
+        rest = &tail[length..];
+    }
+    // the limit may have been lowered since the buffer was written
+    while snapshots.len() > config.max_snapshots {
+        snapshots.pop_front();
+    }
+    snapshots
+}
+
+impl<P: MetricPublisher> MetricPublisher for OfflineBuffer<P> {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        if self.target.is_connected() {
+            self.replay();
+        }
+        if self.snapshots.is_empty() && self.target.is_connected() {
+            self.target.publish(hms_state);
+        } else {
+            debug!("Target is disconnected, buffering snapshot");
+            self.push(hms_state);
+        }
+    }
+
+    fn poll_result(&mut self, host: &str, success: bool) {
+        self.target.poll_result(host, success);
+        // keeps replaying the backlog while the inverters don't reply, e.g. at night
+        if self.target.is_connected() {
+            self.replay();
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.target.is_connected()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::{DropPolicy, OfflineBuffer, OfflineBufferConfig};
+    use crate::{
+        protos::hoymiles::RealData::HMSStateResponse, targets::metric_publisher::MetricPublisher,
+    };
+
+    struct TestTarget {
+        connected: Rc<Cell<bool>>,
+        published: Vec<i32>,
+    }
+
+    impl MetricPublisher for TestTarget {
+        fn publish(&mut self, hms_state: &HMSStateResponse) {
+            self.published.push(hms_state.time);
+        }
+
+        fn is_connected(&self) -> bool {
+            self.connected.get()
+        }
+    }
+
+    fn snapshot(time: i32) -> HMSStateResponse {
+        HMSStateResponse {
+            dtu_sn: "123".into(),
+            time,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_buffer_and_replay() {
+        let file = std::env::temp_dir().join(format!("offline_buffer_{}.bin", std::process::id()));
+        let config = OfflineBufferConfig {
+            file: file.clone(),
+            max_snapshots: 3,
+            drop_policy: DropPolicy::DropOldest,
+            replay_batch_size: 100,
+        };
+        let connected = Rc::new(Cell::new(false));
+        let target = TestTarget {
+            connected: connected.clone(),
+            published: Vec::new(),
+        };
+        let mut buffer = OfflineBuffer::new(target, config.clone());
+        for time in 1..=4 {
+            buffer.publish(&snapshot(time));
+        }
+        assert!(buffer.target.published.is_empty());
+
+        // a restart keeps the buffered snapshots
+        let target = TestTarget {
+            connected: connected.clone(),
+            published: Vec::new(),
+        };
+        let mut buffer = OfflineBuffer::new(target, config);
+        connected.set(true);
+        buffer.publish(&snapshot(5));
+        assert_eq!(buffer.target.published, [2, 3, 4, 5]);
+        assert!(buffer.snapshots.is_empty());
+
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_append_and_replay_in_batches() {
+        let file =
+            std::env::temp_dir().join(format!("offline_buffer_batches_{}.bin", std::process::id()));
+        let config = OfflineBufferConfig {
+            file: file.clone(),
+            max_snapshots: 4,
+            drop_policy: DropPolicy::DropOldest,
+            replay_batch_size: 2,
+        };
+        let connected = Rc::new(Cell::new(false));
+        let target = TestTarget {
+            connected: connected.clone(),
+            published: Vec::new(),
+        };
+        let mut buffer = OfflineBuffer::new(target, config.clone());
+        buffer.publish(&snapshot(1));
+        let record = std::fs::metadata(&file).unwrap().len();
+        for time in 2..=8 {
+            buffer.publish(&snapshot(time));
+        }
+        // appended until the file holds twice the limit, then rewritten
+        assert_eq!(buffer.file_snapshots, 8);
+        assert_eq!(std::fs::metadata(&file).unwrap().len(), 8 * record);
+        buffer.publish(&snapshot(9));
+        assert_eq!(buffer.file_snapshots, 4);
+        assert_eq!(std::fs::metadata(&file).unwrap().len(), 4 * record);
+
+        connected.set(true);
+        buffer.poll_result("dtu", true);
+        assert_eq!(buffer.target.published, [6, 7]);
+        // a restart continues with the snapshots not replayed yet
+        let target = TestTarget {
+            connected: connected.clone(),
+            published: Vec::new(),
+        };
+        let mut buffer = OfflineBuffer::new(target, config);
+        buffer.poll_result("dtu", true);
+        assert_eq!(buffer.target.published, [8, 9]);
+        buffer.publish(&snapshot(10));
+        assert_eq!(buffer.target.published, [8, 9, 10]);
+        assert!(buffer.snapshots.is_empty());
+
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,446 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    sources::metric::{Metric, MetricLevel},
+    targets::{
+        http_retry::{send_with_retries, SendError},
+        metric_filter::MetricFilter,
+        metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias,
+    },
+};
+
+fn default_endpoint() -> String {
+    "http://localhost:4318/v1/metrics".into()
+}
+
+fn default_service_name() -> String {
+    "hms-mqtt-publish".into()
+}
+
+fn default_max_retries() -> u32 {
+    1
+}
+
+fn default_retry_interval() -> u64 {
+    1000
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+/// `AGGREGATION_TEMPORALITY_CUMULATIVE` of the OTLP protocol
+const CUMULATIVE: u8 = 2;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OtlpConfig {
+    /// OTLP/HTTP metrics endpoint of the collector
+    #[serde(default = "default_endpoint")]
+    pub endpoint: String,
+    /// Additional request headers, e.g. for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Value of the `service.name` resource attribute
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Additional resource attributes, e.g. `deployment.environment`
+    #[serde(default)]
+    pub resource_attributes: HashMap<String, String>,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the exported values
+    #[serde(default)]
+    pub filter: MetricFilter,
+    /// Retries of a failed export before the snapshot is dropped. Polling waits for the export,
+    /// so an unreachable collector delays it by up to `(max_retries + 1) * timeout` plus the
+    /// retry delays.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Milliseconds before the first retry, doubled for every further retry
+    #[serde(default = "default_retry_interval")]
+    pub retry_interval: u64,
+    /// Seconds before a request is aborted
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// `Otlp` exports every snapshot to an OpenTelemetry collector using OTLP/HTTP with JSON encoding.
+///
+/// Every DTU and every inverter is a resource of its own, identified by the resource attributes
+/// `hms.dtu.serial` and `hms.inverter.id`. Ports are reported by their inverter.
+pub struct Otlp {
+    config: OtlpConfig,
+    agent: ureq::Agent,
+    start_time: u64,
+    /// Start time, last value and time of every counter, keyed by DTU serial and metric path
+    counters: HashMap<(String, String), Counter>,
+}
+
+/// Counters like the daily yield reset, which starts a new series with a later start time
+struct Counter {
+    start_time: u64,
+    value: f64,
+    time: u64,
+}
+
+/// Returns the unit of the metric in the UCUM notation used by OpenTelemetry
+fn ucum_unit(unit: &str) -> &str {
+    match unit {
+        "°C" => "Cel",
+        unit => unit,
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// 64 bit integers are encoded as strings in OTLP/JSON
+fn int_attribute(key: &str, value: i64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+impl Otlp {
+    pub fn new(config: &OtlpConfig) -> anyhow::Result<Self> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout))
+            .build();
+        Ok(Self {
+            config: config.clone(),
+            agent,
+            start_time: unix_nanos(SystemTime::now()),
+            counters: HashMap::new(),
+        })
+    }
+
+    fn get_request(&mut self, hms_state: &HMSStateResponse) -> Value {
+        let alias = hms_state.get_alias(&self.config.serial_aliases).to_string();
+        // fake inverters and some firmwares report no time
+        let time = match hms_state.time {
+            time if time > 0 => UNIX_EPOCH + Duration::from_secs(time as u64),
+            _ => SystemTime::now(),
+        };
+        let time = unix_nanos(time);
+
+        // metrics of the DTU itself have no inverter
+        let mut resources: BTreeMap<Option<i64>, Vec<Value>> = BTreeMap::new();
+        for metric in hms_state.get_metrics() {
+            if !self
+                .config
+                .filter
+                .is_included(&hms_state.dtu_sn, &alias, &metric.path())
+            {
+                continue;
+            }
+            let (inverter, attributes) = match metric.level {
+                MetricLevel::Dtu => (None, vec![]),
+                MetricLevel::Inverter(id) => (Some(id), vec![]),
+                MetricLevel::Port(port) => {
+                    let inverter = hms_state
+                        .port_state
+                        .iter()
+                        .find(|port_state| port_state.pv_port == port)
+                        .map(|port_state| port_state.pv_sn);
+                    (inverter, vec![int_attribute("hms.port", port as i64)])
+                }
+            };
+            let start_time = self.get_start_time(&hms_state.dtu_sn, &metric, time);
+            let value = get_metric(&metric, attributes, time, start_time);
+            let metrics = resources.entry(inverter).or_default();
+            // ports of an inverter share a metric with a data point per port
+            let kind = if metric.is_instantaneous() {
+                "gauge"
+            } else {
+                "sum"
+            };
+            match metrics
+                .iter_mut()
+                .find(|existing| existing["name"] == value["name"])
+            {
+                Some(existing) => {
+                    if let Some(data_points) = existing[kind]["dataPoints"].as_array_mut() {
+                        data_points.push(value[kind]["dataPoints"][0].clone());
+                    }
+                }
+                None => metrics.push(value),
+            }
+        }
+
+        let resource_metrics: Vec<_> = resources
+            .into_iter()
+            .map(|(inverter, metrics)| {
+                let mut attributes = vec![
+                    string_attribute("service.name", &self.config.service_name),
+                    string_attribute("hms.dtu.serial", &hms_state.dtu_sn),
+                    string_attribute("hms.dtu.alias", &alias),
+                ];
+                if let Some(inverter) = inverter {
+                    attributes.push(int_attribute("hms.inverter.id", inverter));
+                }
+                let mut extra: Vec<_> = self.config.resource_attributes.iter().collect();
+                extra.sort();
+                attributes.extend(
+                    extra
+                        .into_iter()
+                        .map(|(key, value)| string_attribute(key, value)),
+                );
+                json!({
+                    "resource": { "attributes": attributes },
+                    "scopeMetrics": [{
+                        "scope": { "name": "hms2mqtt", "version": env!("CARGO_PKG_VERSION") },
+                        "metrics": metrics,
+                    }],
+                })
+            })
+            .collect();
+        json!({ "resourceMetrics": resource_metrics })
+    }
+
+    /// Returns the start time of a counter, which moves to the previous data point if the
+    /// value dropped, e.g. when the daily yield resets at night
+    fn get_start_time(&mut self, dtu_sn: &str, metric: &Metric, time: u64) -> u64 {
+        if metric.is_instantaneous() {
+            return self.start_time;
+        }
+        let counter = self
+            .counters
+            .entry((dtu_sn.to_string(), metric.path()))
+            .or_insert(Counter {
+                start_time: self.start_time,
+                value: metric.value,
+                time,
+            });
+        if metric.value < counter.value {
+            counter.start_time = counter.time;
+        }
+        counter.value = metric.value;
+        counter.time = time;
+        counter.start_time
+    }
+
+    fn export(&self, body: &str) -> Result<(), SendError> {
+        let mut request = self
+            .agent
+            .post(&self.config.endpoint)
+            .set("Content-Type", "application/json");
+        for (name, value) in &self.config.headers {
+            request = request.set(name, value);
+        }
+        request.send_string(body)?;
+        Ok(())
+    }
+}
+
+fn get_metric(metric: &Metric, attributes: Vec<Value>, time: u64, start_time: u64) -> Value {
+    let component = match metric.level {
+        MetricLevel::Dtu => "dtu",
+        MetricLevel::Inverter(_) => "inverter",
+        MetricLevel::Port(_) => "port",
+    };
+    let data_point = json!({
+        "attributes": attributes,
+        "timeUnixNano": time.to_string(),
+        "asDouble": metric.value,
+    });
+    let mut value = json!({
+        "name": format!("hms.{component}.{}", metric.name),
+        "description": metric.path(),
+        "unit": ucum_unit(metric.unit.unwrap_or_default()),
+    });
+    if metric.is_instantaneous() {
+        value["gauge"] = json!({ "dataPoints": [data_point] });
+    } else {
+        let mut data_point = data_point;
+        data_point["startTimeUnixNano"] = start_time.to_string().into();
+        value["sum"] = json!({
+            "aggregationTemporality": CUMULATIVE,
+            "isMonotonic": true,
+            "dataPoints": [data_point],
+        });
+    }
+    value
+}
+
+impl MetricPublisher for Otlp {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        let body = self.get_request(hms_state).to_string();
+        let result = send_with_retries(
+            "OpenTelemetry collector",
+            self.config.max_retries,
+            self.config.retry_interval,
+            || self.export(&body),
+        );
+        match result {
+            Ok(()) => debug!("Exported metrics of {}", hms_state.dtu_sn),
+            Err(SendError::Retryable(message) | SendError::Rejected(message)) => {
+                warn!("Dropping metrics of {}: {message}", hms_state.dtu_sn)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+
+    use super::{Otlp, OtlpConfig};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, InverterState, PortState},
+        targets::{http_stub::HttpStub, metric_publisher::MetricPublisher},
+    };
+
+    fn attribute<'a>(attributes: &'a Value, key: &str) -> &'a Value {
+        &attributes
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|attribute| attribute["key"] == key)
+            .unwrap()["value"]
+    }
+
+    #[test]
+    fn test_export() {
+        let stub = HttpStub::start(vec![200]);
+        let config: OtlpConfig = serde_yaml::from_str(&format!(
+            r#"
+            endpoint: "{}/v1/metrics"
+            headers: {{Authorization: "Bearer token"}}
+            resource_attributes: {{deployment.environment: home}}
+            filter:
+              include: ["current_power", "inverter/*/temperature", "port/*/energy"]
+            "#,
+            stub.url()
+        ))
+        .unwrap();
+        let mut otlp = Otlp::new(&config).unwrap();
+        otlp.publish(&HMSStateResponse {
+            dtu_sn: "123".into(),
+            time: 1_700_000_000,
+            pv_current_power: 1234,
+            inverter_state: vec![InverterState {
+                inv_id: 42,
+                temperature: 215,
+                ..Default::default()
+            }],
+            port_state: vec![
+                PortState {
+                    pv_sn: 42,
+                    pv_port: 1,
+                    pv_energy_total: 5000,
+                    ..Default::default()
+                },
+                PortState {
+                    pv_sn: 42,
+                    pv_port: 2,
+                    pv_energy_total: 6000,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        });
+
+        let request = &stub.requests()[0];
+        assert_eq!(request.path, "/v1/metrics");
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(request.headers["authorization"], "Bearer token");
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        let resources = body["resourceMetrics"].as_array().unwrap();
+        assert_eq!(resources.len(), 2);
+
+        let dtu_attributes = &resources[0]["resource"]["attributes"];
+        assert_eq!(
+            attribute(dtu_attributes, "service.name")["stringValue"],
+            "hms-mqtt-publish"
+        );
+        assert_eq!(
+            attribute(dtu_attributes, "hms.dtu.serial")["stringValue"],
+            "123"
+        );
+        assert_eq!(
+            attribute(dtu_attributes, "deployment.environment")["stringValue"],
+            "home"
+        );
+        let power = &resources[0]["scopeMetrics"][0]["metrics"][0];
+        assert_eq!(power["name"], "hms.dtu.current_power");
+        assert_eq!(power["unit"], "W");
+        assert_eq!(power["gauge"]["dataPoints"][0]["asDouble"], 123.4);
+        assert_eq!(
+            power["gauge"]["dataPoints"][0]["timeUnixNano"],
+            "1700000000000000000"
+        );
+
+        let inverter = &resources[1];
+        assert_eq!(
+            attribute(&inverter["resource"]["attributes"], "hms.inverter.id")["intValue"],
+            "42"
+        );
+        let metrics = &inverter["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "hms.inverter.temperature");
+        assert_eq!(metrics[0]["unit"], "Cel");
+        assert_eq!(metrics[1]["name"], "hms.port.energy");
+        assert_eq!(metrics[1]["sum"]["isMonotonic"], true);
+        assert_eq!(
+            attribute(
+                &metrics[1]["sum"]["dataPoints"][0]["attributes"],
+                "hms.port"
+            )["intValue"],
+            "1"
+        );
+    }
+
+    #[test]
+    fn test_daily_yield_reset() {
+        let stub = HttpStub::start(vec![200, 200, 200]);
+        let config: OtlpConfig = serde_yaml::from_str(&format!(
+            "endpoint: '{}'\nfilter: {{include: [daily_yield]}}",
+            stub.url()
+        ))
+        .unwrap();
+        let mut otlp = Otlp::new(&config).unwrap();
+        for (time, pv_daily_yield) in [
+            (1_700_000_000, 500),
+            (1_700_000_300, 600),
+            (1_700_080_000, 0),
+        ] {
+            otlp.publish(&HMSStateResponse {
+                dtu_sn: "123".into(),
+                time,
+                pv_daily_yield,
+                ..Default::default()
+            });
+        }
+
+        let start_times: Vec<_> = stub
+            .requests()
+            .iter()
+            .map(|request| {
+                let body: Value = serde_json::from_slice(&request.body).unwrap();
+                let metric = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0];
+                assert_eq!(metric["name"], "hms.dtu.daily_yield");
+                metric["sum"]["dataPoints"][0]["startTimeUnixNano"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(start_times[0], start_times[1]);
+        // the new series starts with the last data point of the previous day
+        assert_eq!(start_times[2], "1700000300000000000");
+    }
+}
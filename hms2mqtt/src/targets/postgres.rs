@@ -0,0 +1,403 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use log::{debug, info, warn};
+use postgres::{config::SslMode, Client, NoTls};
+use serde::Deserialize;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    sources::metric::MetricLevel,
+    targets::{
+        metric_filter::MetricFilter, metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias, offline_buffer::OfflineBufferConfig,
+    },
+};
+
+fn default_table() -> String {
+    "hms_readings".into()
+}
+
+fn default_hypertable() -> bool {
+    true
+}
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+/// Used unless the connection string sets `connect_timeout`, connecting blocks the polling
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PostgresConfig {
+    /// e.g. `host=localhost user=hms dbname=pv` or `postgresql://hms@localhost/pv`.
+    /// TLS is not supported, `sslmode=require` is rejected.
+    pub connection_string: String,
+    /// Table the readings are inserted into, created if missing
+    #[serde(default = "default_table")]
+    pub table: String,
+    /// Turns the table into a TimescaleDB hypertable partitioned by time
+    #[serde(default = "default_hypertable")]
+    pub hypertable: bool,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the stored values
+    #[serde(default)]
+    pub filter: MetricFilter,
+    /// Buffers snapshots on disk while the database is unreachable
+    pub offline_buffer: Option<OfflineBufferConfig>,
+}
+
+/// A row of the readings table
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    inverter: Option<i64>,
+    port: Option<i32>,
+    metric: &'static str,
+    value: f64,
+    unit: Option<&'static str>,
+}
+
+/// `Postgres` inserts every snapshot into a PostgreSQL or TimescaleDB table.
+///
+/// The connection is established lazily and re-established after failures with an increasing
+/// delay, so the database may be unreachable on startup.
+pub struct Postgres {
+    config: PostgresConfig,
+    connection_config: postgres::Config,
+    client: Option<Client>,
+    reconnect_delay: Duration,
+    next_connect: Instant,
+}
+
+impl Postgres {
+    pub fn new(config: &PostgresConfig) -> anyhow::Result<Self> {
+        // the table name is part of the statements and can't be a parameter
+        let valid_identifier = |identifier: &str| {
+            identifier
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && identifier
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if !config.table.split('.').all(valid_identifier) || config.table.split('.').count() > 2 {
+            bail!("invalid table name '{}'", config.table);
+        }
+        let mut connection_config: postgres::Config = config.connection_string.parse()?;
+        // NoTls falls back to an unencrypted connection for `prefer`, but fails on every
+        // connect for `require`
+        if !matches!(
+            connection_config.get_ssl_mode(),
+            SslMode::Disable | SslMode::Prefer
+        ) {
+            bail!("TLS connections to PostgreSQL are not supported, use sslmode=disable or prefer");
+        }
+        if connection_config.get_connect_timeout().is_none() {
+            connection_config.connect_timeout(DEFAULT_CONNECT_TIMEOUT);
+        }
+
+        let mut postgres = Self {
+            config: config.clone(),
+            connection_config,
+            client: None,
+            reconnect_delay: MIN_RECONNECT_DELAY,
+            next_connect: Instant::now(),
+        };
+        postgres.ensure_connected();
+        Ok(postgres)
+    }
+
+    fn connect(&self) -> anyhow::Result<Client> {
+        let mut client = self.connection_config.connect(NoTls)?;
+        let table = &self.config.table;
+        let index = format!("{}_serial_metric_time", table.replace('.', "_"));
+        client.batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {table} (
+                time TIMESTAMPTZ NOT NULL,
+                serial TEXT NOT NULL,
+                alias TEXT NOT NULL,
+                inverter BIGINT,
+                port INTEGER,
+                metric TEXT NOT NULL,
+                value DOUBLE PRECISION NOT NULL,
+                unit TEXT
+            );
+            CREATE INDEX IF NOT EXISTS {index} ON {table} (serial, metric, time DESC);"
+        ))?;
+        if self.config.hypertable {
+            client.batch_execute(&format!(
+                "CREATE EXTENSION IF NOT EXISTS timescaledb;
+                 SELECT create_hypertable('{table}', 'time', if_not_exists => TRUE);"
+            ))?;
+        }
+        Ok(client)
+    }
+
+    /// Connects unless connected or the last failed attempt was too recent
+    fn ensure_connected(&mut self) {
+        if self.is_connected() || Instant::now() < self.next_connect {
+            return;
+        }
+        match self.connect() {
+            Ok(client) => {
+                info!("Connected to PostgreSQL");
+                self.client = Some(client);
+                self.reconnect_delay = MIN_RECONNECT_DELAY;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to connect to PostgreSQL, retrying in {:?}: {e}",
+                    self.reconnect_delay
+                );
+                self.client = None;
+                self.next_connect = Instant::now() + self.reconnect_delay;
+                self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+
+    fn get_rows(&self, hms_state: &HMSStateResponse) -> Vec<Row> {
+        let alias = hms_state.get_alias(&self.config.serial_aliases);
+        hms_state
+            .get_metrics()
+            .into_iter()
+            .filter(|metric| {
+                self.config
+                    .filter
+                    .is_included(&hms_state.dtu_sn, alias, &metric.path())
+            })
+            .map(|metric| {
+                let (inverter, port) = match metric.level {
+                    MetricLevel::Dtu => (None, None),
+                    MetricLevel::Inverter(id) => (Some(id), None),
+                    MetricLevel::Port(port) => (None, Some(port)),
+                };
+                Row {
+                    inverter,
+                    port,
+                    metric: metric.name,
+                    value: metric.value,
+                    unit: metric.unit,
+                }
+            })
+            .collect()
+    }
+
+    fn insert(
+        &mut self,
+        hms_state: &HMSStateResponse,
+        rows: &[Row],
+    ) -> Result<(), postgres::Error> {
+        let Some(client) = &mut self.client else {
+            return Ok(());
+        };
+        // fake inverters and some firmwares report no time
+        let time = match hms_state.time {
+            time if time > 0 => UNIX_EPOCH + Duration::from_secs(time as u64),
+            _ => SystemTime::now(),
+        };
+        let alias = hms_state.get_alias(&self.config.serial_aliases);
+
+        let mut transaction = client.transaction()?;
+        let statement = transaction.prepare(&format!(
+            "INSERT INTO {} (time, serial, alias, inverter, port, metric, value, unit)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            self.config.table
+        ))?;
+        for row in rows {
+            transaction.execute(
+                &statement,
+                &[
+                    &time,
+                    &hms_state.dtu_sn,
+                    &alias,
+                    &row.inverter,
+                    &row.port,
+                    &row.metric,
+                    &row.value,
+                    &row.unit,
+                ],
+            )?;
+        }
+        transaction.commit()
+    }
+}
+
+impl MetricPublisher for Postgres {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        let rows = self.get_rows(hms_state);
+        // the connection may have been closed since the last snapshot, so reconnect once
+        for _ in 0..2 {
+            self.ensure_connected();
+            if !self.is_connected() {
+                break;
+            }
+            match self.insert(hms_state, &rows) {
+                Ok(()) => {
+                    debug!("Inserted {} readings of {}", rows.len(), hms_state.dtu_sn);
+                    return;
+                }
+                Err(e) if self.client.as_ref().is_some_and(Client::is_closed) => {
+                    warn!("Lost connection to PostgreSQL: {e}");
+                    self.client = None;
+                }
+                Err(e) => {
+                    warn!("Failed to insert readings of {}: {e}", hms_state.dtu_sn);
+                    return;
+                }
+            }
+        }
+        warn!(
+            "Dropping snapshot of {}, PostgreSQL is unreachable",
+            hms_state.dtu_sn
+        );
+    }
+
+    fn poll_result(&mut self, _host: &str, _success: bool) {
+        // reconnect in time for a replay of the offline buffer
+        self.ensure_connected();
+    }
+
+    fn is_connected(&self) -> bool {
+        self.client
+            .as_ref()
+            .is_some_and(|client| !client.is_closed())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::TcpListener,
+        time::{Duration, Instant},
+    };
+
+    use super::{Postgres, PostgresConfig, Row, DEFAULT_CONNECT_TIMEOUT};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, PortState},
+        targets::{
+            metric_publisher::MetricPublisher,
+            offline_buffer::{OfflineBuffer, OfflineBufferConfig},
+        },
+    };
+
+    fn config(table: &str) -> PostgresConfig {
+        config_with_connection(table, "host=127.0.0.1 port=1 user=hms connect_timeout=1")
+    }
+
+    fn config_with_connection(table: &str, connection_string: &str) -> PostgresConfig {
+        serde_yaml::from_str(&format!(
+            r#"
+            connection_string: "{connection_string}"
+            table: "{table}"
+            filter:
+              include: ["current_power", "port/*/power"]
+            "#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rows_and_unreachable_database() {
+        assert!(Postgres::new(&config("readings; DROP TABLE x")).is_err());
+
+        // the database may be unreachable on startup
+        let postgres = Postgres::new(&config("energy.readings")).unwrap();
+        assert!(postgres.client.is_none());
+        let rows = postgres.get_rows(&HMSStateResponse {
+            dtu_sn: "123".into(),
+            pv_current_power: 1234,
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_power: 617,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        assert_eq!(
+            rows,
+            [
+                Row {
+                    inverter: None,
+                    port: None,
+                    metric: "current_power",
+                    value: 123.4,
+                    unit: Some("W"),
+                },
+                Row {
+                    inverter: None,
+                    port: Some(1),
+                    metric: "power",
+                    value: 61.7,
+                    unit: Some("W"),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_connection_options() {
+        let tls = config_with_connection("readings", "host=127.0.0.1 sslmode=require");
+        let error = Postgres::new(&tls).err().unwrap();
+        assert!(error.to_string().contains("TLS"), "{error}");
+
+        let postgres = Postgres::new(&config_with_connection(
+            "readings",
+            "host=127.0.0.1 port=1 sslmode=prefer",
+        ))
+        .unwrap();
+        assert_eq!(
+            postgres.connection_config.get_connect_timeout(),
+            Some(&DEFAULT_CONNECT_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn test_publish_while_unreachable() {
+        // a port nobody listens on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = config_with_connection(
+            "readings",
+            &format!("host=127.0.0.1 port={port} user=hms connect_timeout=1"),
+        );
+        let file = std::env::temp_dir().join(format!("postgres_buffer_{}.bin", std::process::id()));
+        let buffer_config = OfflineBufferConfig {
+            file: file.clone(),
+            max_snapshots: 10,
+            drop_policy: Default::default(),
+            replay_batch_size: 10,
+        };
+
+        let postgres = Postgres::new(&config).unwrap();
+        assert!(!postgres.is_connected());
+        let mut buffer = OfflineBuffer::new(postgres, buffer_config);
+        assert!(!buffer.is_connected());
+
+        // the failed connection attempt on startup delays the next one, so publishing
+        // buffers the snapshot without blocking
+        let start = Instant::now();
+        buffer.publish(&HMSStateResponse {
+            dtu_sn: "123".into(),
+            time: 1_700_000_000,
+            ..Default::default()
+        });
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(std::fs::metadata(&file).unwrap().len() > 0);
+
+        // publishing directly drops the snapshot
+        let mut postgres = Postgres::new(&config).unwrap();
+        postgres.publish(&HMSStateResponse::default());
+        assert!(!postgres.is_connected());
+
+        std::fs::remove_file(file).unwrap();
+    }
+}
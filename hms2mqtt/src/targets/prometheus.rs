@@ -0,0 +1,408 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    sources::metric::{Metric, MetricLevel},
+    targets::{
+        metric_filter::MetricFilter, metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias,
+    },
+};
+
+/// Time a client has to send its request, so idle connections don't pile up
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_listen() -> String {
+    "0.0.0.0:9184".into()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PrometheusConfig {
+    /// Address the `/metrics` endpoint listens on
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the exported values
+    #[serde(default)]
+    pub filter: MetricFilter,
+}
+
+/// Last snapshot of a DTU
+struct DtuValues {
+    alias: String,
+    metrics: Vec<Metric>,
+    received: Instant,
+}
+
+#[derive(Default)]
+struct PollResults {
+    successes: u64,
+    failures: u64,
+    last_success: bool,
+}
+
+#[derive(Default)]
+struct State {
+    dtus: BTreeMap<String, DtuValues>,
+    polls: BTreeMap<String, PollResults>,
+}
+
+/// `Prometheus` serves the latest values of every DTU on `/metrics`.
+pub struct Prometheus {
+    config: PrometheusConfig,
+    state: Arc<Mutex<State>>,
+    local_addr: SocketAddr,
+}
+
+impl Prometheus {
+    pub fn new(config: &PrometheusConfig) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(&config.listen)
+            .with_context(|| format!("could not listen on {}", config.listen))?;
+        let local_addr = listener.local_addr()?;
+        info!("Serving Prometheus metrics on http://{local_addr}/metrics");
+
+        let state = Arc::new(Mutex::new(State::default()));
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Failed to accept Prometheus connection: {e}");
+                        continue;
+                    }
+                };
+                // a slow client must not delay the others
+                let state = server_state.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_request(stream, &state) {
+                        warn!("Failed to serve Prometheus request: {e}");
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            config: config.clone(),
+            state,
+            local_addr,
+        })
+    }
+
+    /// Address the endpoint is bound to, useful with port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn handle_request(stream: TcpStream, state: &Mutex<State>) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers, requests have no body
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    debug!("Prometheus request for {path}");
+    let (status, content_type, body) = match path {
+        "/metrics" => {
+            let state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            (
+                "200 OK",
+                "text/plain; version=0.0.4; charset=utf-8",
+                render(&state, Instant::now()),
+            )
+        }
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+fn unit_suffix(unit: &str) -> Option<&'static str> {
+    match unit {
+        "W" => Some("watts"),
+        "Wh" => Some("watt_hours"),
+        "V" => Some("volts"),
+        "A" => Some("amperes"),
+        "Hz" => Some("hertz"),
+        "°C" => Some("celsius"),
+        "s" => Some("seconds"),
+        _ => None,
+    }
+}
+
+/// Returns the Prometheus name of the metric, e.g. `hms_port_power_watts`
+fn metric_name(metric: &Metric) -> String {
+    let component = match metric.level {
+        MetricLevel::Dtu => "dtu",
+        MetricLevel::Inverter(_) => "inverter",
+        MetricLevel::Port(_) => "port",
+    };
+    let mut name = format!("hms_{component}_{}", metric.name);
+    if let Some(suffix) = metric.unit.and_then(unit_suffix) {
+        name = format!("{name}_{suffix}");
+    }
+    if !metric.is_instantaneous() {
+        name.push_str("_total");
+    }
+    name
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn labels(pairs: &[(&str, String)]) -> String {
+    let labels: Vec<_> = pairs
+        .iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", escape_label(value)))
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Samples of one metric family
+struct Family {
+    kind: &'static str,
+    help: String,
+    samples: Vec<(String, f64)>,
+}
+
+fn render(state: &State, now: Instant) -> String {
+    let mut families: BTreeMap<String, Family> = BTreeMap::new();
+    let mut add = |name: String, kind, help: String, labels: String, value| {
+        families
+            .entry(name)
+            .or_insert_with(|| Family {
+                kind,
+                help,
+                samples: Vec::new(),
+            })
+            .samples
+            .push((labels, value));
+    };
+
+    for (serial, dtu) in &state.dtus {
+        let dtu_labels = [("serial", serial.clone()), ("alias", dtu.alias.clone())];
+        for metric in &dtu.metrics {
+            let mut pairs = dtu_labels.to_vec();
+            match metric.level {
+                MetricLevel::Dtu => {}
+                MetricLevel::Inverter(id) => pairs.push(("inverter", id.to_string())),
+                MetricLevel::Port(port) => pairs.push(("port", port.to_string())),
+            }
+            let kind = if metric.is_instantaneous() {
+                "gauge"
+            } else {
+                "counter"
+            };
+            let help = match metric.unit {
+                Some(unit) => format!("{} in {unit}", metric.path()),
+                None => metric.path(),
+            };
+            add(
+                metric_name(metric),
+                kind,
+                help,
+                labels(&pairs),
+                metric.value,
+            );
+        }
+        add(
+            "hms_data_age_seconds".into(),
+            "gauge",
+            "Seconds since the last values of the DTU were received".into(),
+            labels(&dtu_labels),
+            now.duration_since(dtu.received).as_secs_f64(),
+        );
+    }
+
+    for (host, polls) in &state.polls {
+        let host_labels = labels(&[("host", host.clone())]);
+        add(
+            "hms_polls_total".into(),
+            "counter",
+            "Polls of the inverter".into(),
+            host_labels.clone(),
+            (polls.successes + polls.failures) as f64,
+        );
+        add(
+            "hms_poll_failures_total".into(),
+            "counter",
+            "Failed polls of the inverter".into(),
+            host_labels.clone(),
+            polls.failures as f64,
+        );
+        add(
+            "hms_poll_success".into(),
+            "gauge",
+            "Whether the last poll of the inverter succeeded".into(),
+            host_labels,
+            if polls.last_success { 1. } else { 0. },
+        );
+    }
+
+    let mut output = String::new();
+    for (name, family) in families {
+        let _ = writeln!(output, "# HELP {name} {}", family.help);
+        let _ = writeln!(output, "# TYPE {name} {}", family.kind);
+        for (labels, value) in family.samples {
+            let _ = writeln!(output, "{name}{labels} {value}");
+        }
+    }
+    output
+}
+
+impl MetricPublisher for Prometheus {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        let alias = hms_state.get_alias(&self.config.serial_aliases).to_string();
+        let metrics = hms_state
+            .get_metrics()
+            .into_iter()
+            .filter(|metric| {
+                self.config
+                    .filter
+                    .is_included(&hms_state.dtu_sn, &alias, &metric.path())
+            })
+            .collect();
+        self.lock_state().dtus.insert(
+            hms_state.dtu_sn.clone(),
+            DtuValues {
+                alias,
+                metrics,
+                received: Instant::now(),
+            },
+        );
+    }
+
+    fn poll_result(&mut self, host: &str, success: bool) {
+        let mut state = self.lock_state();
+        let polls = state.polls.entry(host.to_string()).or_default();
+        if success {
+            polls.successes += 1;
+        } else {
+            polls.failures += 1;
+        }
+        polls.last_success = success;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        time::Duration,
+    };
+
+    use super::{Prometheus, PrometheusConfig};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, InverterState, PortState},
+        targets::metric_publisher::MetricPublisher,
+    };
+
+    #[test]
+    fn test_serve_metrics() {
+        let config: PrometheusConfig = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:0"
+            serial_aliases:
+              - serial: "123"
+                alias: "roof"
+            "#,
+        )
+        .unwrap();
+        let mut prometheus = Prometheus::new(&config).unwrap();
+        prometheus.publish(&HMSStateResponse {
+            dtu_sn: "123".into(),
+            pv_current_power: 1234,
+            inverter_state: vec![InverterState {
+                inv_id: 42,
+                temperature: 215,
+                ..Default::default()
+            }],
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_energy_total: 5000,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        prometheus.poll_result("192.168.1.2", true);
+        prometheus.poll_result("192.168.1.2", false);
+
+        let mut stream = TcpStream::connect(prometheus.local_addr()).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        for line in [
+            "# TYPE hms_dtu_current_power_watts gauge",
+            r#"hms_dtu_current_power_watts{serial="123",alias="roof"} 123.4"#,
+            r#"hms_inverter_temperature_celsius{serial="123",alias="roof",inverter="42"} 21.5"#,
+            "# TYPE hms_port_energy_watt_hours_total counter",
+            r#"hms_port_energy_watt_hours_total{serial="123",alias="roof",port="1"} 5000"#,
+            r#"hms_polls_total{host="192.168.1.2"} 2"#,
+            r#"hms_poll_failures_total{host="192.168.1.2"} 1"#,
+            r#"hms_poll_success{host="192.168.1.2"} 0"#,
+        ] {
+            assert!(response.contains(line), "missing {line} in {response}");
+        }
+        assert!(response.contains(r#"hms_data_age_seconds{serial="123",alias="roof"}"#));
+    }
+
+    #[test]
+    fn test_idle_connection_does_not_block() {
+        let config: PrometheusConfig = serde_yaml::from_str(r#"listen: "127.0.0.1:0""#).unwrap();
+        let prometheus = Prometheus::new(&config).unwrap();
+
+        // connects without sending a complete request
+        let mut idle = TcpStream::connect(prometheus.local_addr()).unwrap();
+        idle.write_all(b"GET /met").unwrap();
+
+        let mut stream = TcpStream::connect(prometheus.local_addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+}
@@ -0,0 +1,658 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use chrono::{DateTime, Local, Timelike};
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    targets::{http_retry::SendError, metric_filter::Pattern, metric_publisher::MetricPublisher},
+};
+
+fn default_url() -> String {
+    "https://pvoutput.org/service/r2/addbatchstatus.jsp".into()
+}
+
+fn default_status_interval() -> u32 {
+    5
+}
+
+fn default_extended_fields() -> BTreeMap<String, Pattern> {
+    BTreeMap::from([
+        ("v7".into(), "inverter/*/temperature".parse().unwrap()),
+        ("v8".into(), "inverter/*/grid_voltage".parse().unwrap()),
+    ])
+}
+
+fn default_requests_per_hour() -> usize {
+    60
+}
+
+fn default_max_batch_size() -> usize {
+    30
+}
+
+fn default_max_age_days() -> u32 {
+    14
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+/// Extended fields are v7 to v12, v1 to v6 are the standard fields of a status
+const EXTENDED_FIELDS: [&str; 6] = ["v7", "v8", "v9", "v10", "v11", "v12"];
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PvOutputConfig {
+    /// Batch status service, can be changed for testing
+    #[serde(default = "default_url")]
+    pub url: String,
+    pub api_key: String,
+    /// System of the first DTU without an entry in `system_ids`. PVOutput expects the values
+    /// of one system, so further DTUs without an entry are not uploaded.
+    pub system_id: Option<String>,
+    /// Systems keyed by DTU serial
+    #[serde(default)]
+    pub system_ids: HashMap<String, String>,
+    /// Minutes aggregated into a status, must match the status interval of the system
+    #[serde(default = "default_status_interval")]
+    pub status_interval: u32,
+    /// Extended fields `v7` to `v12` with the metrics averaged into them,
+    /// e.g. `inverter/*/temperature`. Extended data requires a donation to PVOutput.
+    #[serde(default = "default_extended_fields")]
+    pub extended_fields: BTreeMap<String, Pattern>,
+    /// Requests allowed per hour and system, 300 with a donation
+    #[serde(default = "default_requests_per_hour")]
+    pub requests_per_hour: usize,
+    /// Statuses per request, 100 with a donation
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+    /// Statuses older than this are dropped, PVOutput refuses them anyway. 90 with a donation.
+    #[serde(default = "default_max_age_days")]
+    pub max_age_days: u32,
+    /// Seconds before a request is aborted
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// Average of the values added during an interval
+#[derive(Debug, Default, Clone, Copy)]
+struct Mean {
+    sum: f64,
+    count: u32,
+}
+
+impl Mean {
+    fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn get(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+/// Snapshots of a DTU received during the current status interval
+#[derive(Debug)]
+struct Interval {
+    system_id: String,
+    /// Unix timestamp of the start
+    start: i64,
+    /// Arrival of the last snapshot, the interval is queued once its DTU stops replying
+    last_update: Instant,
+    /// Highest energy generated today
+    energy: Option<f64>,
+    power: Mean,
+    extended: [Mean; 6],
+}
+
+/// A status as sent to PVOutput
+#[derive(Debug, Clone, PartialEq)]
+struct Status {
+    /// Unix timestamp of the end of the interval
+    time: i64,
+    /// Energy generated today in Wh
+    energy: Option<f64>,
+    /// Average power in W
+    power: Option<f64>,
+    extended: [Option<f64>; 6],
+}
+
+impl Status {
+    /// Returns the status in the format of the batch status service, `d,t,v1,...,v12`
+    fn to_batch_entry(&self) -> String {
+        let time = DateTime::from_timestamp(self.time, 0)
+            .unwrap_or_default()
+            .with_timezone(&Local);
+        // the last interval of a day has to be reported on that day
+        let (date, time) = if time.hour() == 0 && time.minute() == 0 {
+            let last_minute = time - chrono::Duration::minutes(1);
+            (last_minute.format("%Y%m%d"), last_minute.format("%H:%M"))
+        } else {
+            (time.format("%Y%m%d"), time.format("%H:%M"))
+        };
+        let format = |value: Option<f64>, decimals: usize| {
+            value.map_or(String::new(), |value| format!("{value:.decimals$}"))
+        };
+        let mut fields = vec![
+            date.to_string(),
+            time.to_string(),
+            format(self.energy, 0),
+            format(self.power, 0),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+        ];
+        fields.extend(self.extended.iter().map(|value| format(*value, 2)));
+        while fields.last().is_some_and(String::is_empty) {
+            fields.pop();
+        }
+        fields.join(",")
+    }
+}
+
+/// Limits the requests to the configured number within any hour
+struct RateLimiter {
+    limit: usize,
+    requests: VecDeque<Instant>,
+    blocked_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    const WINDOW: Duration = Duration::from_secs(3600);
+
+    /// Returns whether another request is allowed now and counts it if so
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        if self.blocked_until.is_some_and(|until| now < until) {
+            return false;
+        }
+        while self
+            .requests
+            .front()
+            .is_some_and(|request| now.duration_since(*request) >= Self::WINDOW)
+        {
+            self.requests.pop_front();
+        }
+        if self.requests.len() >= self.limit {
+            return false;
+        }
+        self.requests.push_back(now);
+        true
+    }
+
+    /// Blocks requests until the unix timestamp `reset`, or an hour if unknown
+    fn block(&mut self, reset: Option<i64>) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let delay = reset.map_or(Self::WINDOW, |reset| {
+            Duration::from_secs(reset.saturating_sub(now).clamp(0, 3600) as u64)
+        });
+        info!("PVOutput rate limit reached, pausing uploads for {delay:?}");
+        self.blocked_until = Some(Instant::now() + delay);
+    }
+}
+
+/// `PvOutput` uploads the power and daily energy of every DTU to PVOutput.org.
+///
+/// Snapshots are aggregated into statuses of `status_interval` minutes, which are queued and
+/// uploaded in batches. Statuses queued while PVOutput is unreachable or the rate limit is
+/// reached are uploaded later.
+pub struct PvOutput {
+    config: PvOutputConfig,
+    agent: ureq::Agent,
+    /// Current interval keyed by DTU serial
+    intervals: HashMap<String, Interval>,
+    /// DTU using `system_id`
+    fallback_dtu: Option<String>,
+    /// DTUs without a system, to warn only once
+    ignored_dtus: HashSet<String>,
+    /// Statuses not uploaded yet keyed by system id
+    pending: BTreeMap<String, VecDeque<Status>>,
+    rate_limiters: HashMap<String, RateLimiter>,
+}
+
+impl PvOutput {
+    pub fn new(config: &PvOutputConfig) -> anyhow::Result<Self> {
+        if ![5, 10, 15].contains(&config.status_interval) {
+            bail!("status_interval must be 5, 10 or 15 minutes");
+        }
+        if config.system_id.is_none() && config.system_ids.is_empty() {
+            bail!("either system_id or system_ids is required");
+        }
+        if let Some(field) = config
+            .extended_fields
+            .keys()
+            .find(|field| !EXTENDED_FIELDS.contains(&field.as_str()))
+        {
+            bail!("unknown extended field '{field}', expected v7 to v12");
+        }
+        if config.max_batch_size == 0 || config.requests_per_hour == 0 {
+            bail!("max_batch_size and requests_per_hour must be positive");
+        }
+        let mut system_ids = HashSet::new();
+        if let Some(system_id) = config
+            .system_ids
+            .values()
+            .chain(&config.system_id)
+            .find(|system_id| !system_ids.insert(*system_id))
+        {
+            bail!("system {system_id} is configured for more than one DTU");
+        }
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout))
+            .build();
+        Ok(Self {
+            config: config.clone(),
+            agent,
+            intervals: HashMap::new(),
+            fallback_dtu: None,
+            ignored_dtus: HashSet::new(),
+            pending: BTreeMap::new(),
+            rate_limiters: HashMap::new(),
+        })
+    }
+
+    /// Returns the system of the DTU, `system_id` is only used by the first DTU without an entry
+    /// in `system_ids`
+    fn get_system_id(&mut self, dtu_sn: &str) -> Option<String> {
+        if let Some(system_id) = self.config.system_ids.get(dtu_sn) {
+            return Some(system_id.clone());
+        }
+        let system_id = self.config.system_id.as_ref()?;
+        match &self.fallback_dtu {
+            Some(fallback_dtu) if fallback_dtu != dtu_sn => {
+                if self.ignored_dtus.insert(dtu_sn.to_string()) {
+                    warn!(
+                        "Not uploading DTU {dtu_sn} to PVOutput, system {system_id} is used by DTU {fallback_dtu}. Add it to system_ids."
+                    );
+                }
+                None
+            }
+            _ => {
+                self.fallback_dtu = Some(dtu_sn.to_string());
+                Some(system_id.clone())
+            }
+        }
+    }
+
+    /// Queues the average values of a finished interval as status
+    fn queue(&mut self, dtu_sn: &str, interval: Interval) {
+        let length = self.config.status_interval as i64 * 60;
+        let status = Status {
+            time: interval.start + length,
+            energy: interval.energy,
+            power: interval.power.get(),
+            extended: interval.extended.map(|mean| mean.get()),
+        };
+        debug!("Queueing PVOutput status {status:?} of {dtu_sn}");
+        self.pending
+            .entry(interval.system_id)
+            .or_default()
+            .push_back(status);
+    }
+
+    /// Queues the intervals of DTUs that did not reply for an interval length, e.g. at night.
+    /// Returns whether any interval was queued.
+    fn queue_idle_intervals(&mut self) -> bool {
+        let length = Duration::from_secs(self.config.status_interval as u64 * 60);
+        let idle: Vec<_> = self
+            .intervals
+            .iter()
+            .filter(|(_, interval)| interval.last_update.elapsed() >= length)
+            .map(|(dtu_sn, _)| dtu_sn.clone())
+            .collect();
+        for dtu_sn in &idle {
+            if let Some(interval) = self.intervals.remove(dtu_sn) {
+                self.queue(dtu_sn, interval);
+            }
+        }
+        !idle.is_empty()
+    }
+
+    /// Adds the snapshot to the current interval of its DTU and queues the previous interval
+    fn aggregate(&mut self, hms_state: &HMSStateResponse) {
+        let Some(system_id) = self.get_system_id(&hms_state.dtu_sn) else {
+            return;
+        };
+        // fake inverters and some firmwares report no time
+        let time = match hms_state.time {
+            time if time > 0 => time as i64,
+            _ => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64,
+        };
+        let length = self.config.status_interval as i64 * 60;
+        let start = time - time.rem_euclid(length);
+
+        if self
+            .intervals
+            .get(&hms_state.dtu_sn)
+            .is_some_and(|interval| interval.start != start)
+        {
+            if let Some(interval) = self.intervals.remove(&hms_state.dtu_sn) {
+                self.queue(&hms_state.dtu_sn, interval);
+            }
+        }
+
+        let interval = self
+            .intervals
+            .entry(hms_state.dtu_sn.clone())
+            .or_insert_with(|| Interval {
+                system_id,
+                start,
+                last_update: Instant::now(),
+                energy: None,
+                power: Mean::default(),
+                extended: [Mean::default(); 6],
+            });
+        interval.last_update = Instant::now();
+        for metric in hms_state.get_metrics() {
+            let path = metric.path();
+            match path.as_str() {
+                "current_power" => interval.power.add(metric.value),
+                "daily_yield" => {
+                    interval.energy = Some(interval.energy.unwrap_or(0.).max(metric.value))
+                }
+                _ => {}
+            }
+            for (field, pattern) in &self.config.extended_fields {
+                if pattern.is_match(&path) {
+                    if let Some(index) = EXTENDED_FIELDS.iter().position(|name| name == field) {
+                        interval.extended[index].add(metric.value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(
+        &self,
+        system_id: &str,
+        statuses: &[Status],
+        rate_limiter: &mut RateLimiter,
+    ) -> Result<(), SendError> {
+        let data: Vec<_> = statuses.iter().map(Status::to_batch_entry).collect();
+        let result = self
+            .agent
+            .post(&self.config.url)
+            .set("X-Pvoutput-Apikey", &self.config.api_key)
+            .set("X-Pvoutput-SystemId", system_id)
+            .set("X-Rate-Limit", "1")
+            .send_form(&[("data", &data.join(";"))]);
+        let reset = |response: &ureq::Response| {
+            response
+                .header("X-Rate-Limit-Reset")
+                .and_then(|reset| reset.parse().ok())
+        };
+        match result {
+            Ok(response) => {
+                if response.header("X-Rate-Limit-Remaining") == Some("0") {
+                    rate_limiter.block(reset(&response));
+                }
+                // every status is answered with `date,time,1` if added and `date,time,0` if not
+                let body = response.into_string().unwrap_or_default();
+                let refused = body
+                    .split(';')
+                    .filter(|status| status.trim().ends_with(",0"))
+                    .count();
+                if refused > 0 {
+                    warn!(
+                        "PVOutput did not add {refused} of {} statuses",
+                        statuses.len()
+                    );
+                }
+                Ok(())
+            }
+            Err(ureq::Error::Status(403, response))
+                if response.header("X-Rate-Limit-Remaining") == Some("0") =>
+            {
+                rate_limiter.block(reset(&response));
+                Err(SendError::Retryable(
+                    "status 403: rate limit exceeded".into(),
+                ))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Uploads queued statuses in batches as far as the rate limits allow
+    fn upload(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let oldest = now - self.config.max_age_days as i64 * 24 * 3600;
+        let mut pending = std::mem::take(&mut self.pending);
+        let mut rate_limiters = std::mem::take(&mut self.rate_limiters);
+        for (system_id, statuses) in &mut pending {
+            let expired = statuses
+                .iter()
+                .take_while(|status| status.time < oldest)
+                .count();
+            if expired > 0 {
+                warn!(
+                    "Dropping {expired} PVOutput statuses older than {} days",
+                    self.config.max_age_days
+                );
+                statuses.drain(..expired);
+            }
+            while !statuses.is_empty() {
+                let limit = self.config.requests_per_hour;
+                let rate_limiter =
+                    rate_limiters
+                        .entry(system_id.clone())
+                        .or_insert_with(|| RateLimiter {
+                            limit,
+                            requests: VecDeque::new(),
+                            blocked_until: None,
+                        });
+                if !rate_limiter.try_acquire() {
+                    debug!(
+                        "Rate limit of system {system_id} reached, {} statuses queued",
+                        statuses.len()
+                    );
+                    break;
+                }
+                let count = statuses.len().min(self.config.max_batch_size);
+                let batch: Vec<_> = statuses.iter().take(count).cloned().collect();
+                match self.send(system_id, &batch, rate_limiter) {
+                    Ok(()) => {
+                        debug!("Uploaded {count} statuses to system {system_id}");
+                        statuses.drain(..count);
+                    }
+                    Err(SendError::Rejected(message)) => {
+                        warn!("Dropping {count} statuses refused by PVOutput: {message}");
+                        statuses.drain(..count);
+                    }
+                    Err(SendError::Retryable(message)) => {
+                        warn!(
+                            "Failed to upload to PVOutput, keeping {} statuses: {message}",
+                            statuses.len()
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        pending.retain(|_, statuses| !statuses.is_empty());
+        self.pending = pending;
+        self.rate_limiters = rate_limiters;
+    }
+}
+
+impl MetricPublisher for PvOutput {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        self.aggregate(hms_state);
+        self.queue_idle_intervals();
+        self.upload();
+    }
+
+    fn poll_result(&mut self, _host: &str, _success: bool) {
+        // DTUs don't reply at night, which would keep the last interval of the day
+        if self.queue_idle_intervals() {
+            self.upload();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{PvOutput, PvOutputConfig, Status};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, InverterState},
+        targets::{http_stub::HttpStub, metric_publisher::MetricPublisher},
+    };
+
+    fn snapshot(time: i32, power: i32, daily_yield: i32) -> HMSStateResponse {
+        HMSStateResponse {
+            dtu_sn: "123".into(),
+            time,
+            pv_current_power: power * 10,
+            pv_daily_yield: daily_yield,
+            inverter_state: vec![
+                InverterState {
+                    inv_id: 1,
+                    temperature: 200,
+                    grid_voltage: 2300,
+                    ..Default::default()
+                },
+                InverterState {
+                    inv_id: 2,
+                    temperature: 300,
+                    grid_voltage: 2320,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    /// Returns the statuses of a form encoded batch request
+    fn statuses(body: &str) -> Vec<String> {
+        body.strip_prefix("data=")
+            .unwrap()
+            .replace("%2C", ",")
+            .replace("%3A", ":")
+            .split("%3B")
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_aggregate_and_upload_batches() {
+        // fails once, then accepts everything
+        let stub = HttpStub::start_with_body(vec![503, 200], "20231114,22:20,1");
+        let config: PvOutputConfig = serde_yaml::from_str(&format!(
+            r#"
+            url: "{}/service/r2/addbatchstatus.jsp"
+            api_key: "key"
+            system_ids: {{"123": "1000"}}
+            requests_per_hour: 3
+            max_batch_size: 2
+            "#,
+            stub.url()
+        ))
+        .unwrap();
+        let mut pvoutput = PvOutput::new(&config).unwrap();
+        // aligned to 15 minutes, current timestamps keep the statuses from expiring
+        let now = chrono::Local::now().timestamp() as i32;
+        let start = now - now % 900 - 3600;
+
+        pvoutput.publish(&snapshot(start, 100, 1000));
+        pvoutput.publish(&snapshot(start + 120, 300, 1010));
+        assert!(stub.requests().is_empty());
+        // starts the next interval, the upload of the first status fails
+        pvoutput.publish(&snapshot(start + 300, 500, 1020));
+        // the failed status is uploaded together with the next one
+        pvoutput.publish(&snapshot(start + 600, 700, 1030));
+        pvoutput.publish(&snapshot(start + 900, 900, 1040));
+        // the rate limit is reached, so the status stays queued
+        pvoutput.publish(&snapshot(start + 1200, 900, 1050));
+
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].path, "/service/r2/addbatchstatus.jsp");
+        assert_eq!(requests[0].headers["x-pvoutput-apikey"], "key");
+        assert_eq!(requests[0].headers["x-pvoutput-systemid"], "1000");
+        let expected = |time: i32, energy: i32, power: i32| {
+            Status {
+                time: time as i64,
+                energy: Some(energy as f64),
+                power: Some(power as f64),
+                extended: [Some(25.), Some(231.), None, None, None, None],
+            }
+            .to_batch_entry()
+        };
+        let first = expected(start + 300, 1010, 200);
+        assert!(first.ends_with(",1010,200,,,,,25.00,231.00"));
+        assert_eq!(statuses(&requests[0].body_text()), [first.as_str()]);
+        assert_eq!(
+            statuses(&requests[1].body_text()),
+            [first, expected(start + 600, 1020, 500)]
+        );
+        assert_eq!(
+            statuses(&requests[2].body_text()),
+            [expected(start + 900, 1030, 700)]
+        );
+        assert_eq!(pvoutput.pending["1000"].len(), 1);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = |extra: &str| -> PvOutputConfig {
+            serde_yaml::from_str(&format!("api_key: \"key\"\nsystem_id: \"1\"\n{extra}")).unwrap()
+        };
+        assert!(PvOutput::new(&config("")).is_ok());
+        assert!(PvOutput::new(&config("status_interval: 7")).is_err());
+        assert!(PvOutput::new(&config("extended_fields: {v13: \"temperature\"}")).is_err());
+        assert!(PvOutput::new(&config("system_ids: {\"123\": \"1\"}")).is_err());
+        assert!(PvOutput::new(&config("system_ids: {\"123\": \"2\", \"456\": \"2\"}")).is_err());
+    }
+
+    #[test]
+    fn test_queue_last_interval_and_single_fallback() {
+        let stub = HttpStub::start_with_body(vec![200], "20231114,22:20,1");
+        let config: PvOutputConfig = serde_yaml::from_str(&format!(
+            "url: \"{}\"\napi_key: \"key\"\nsystem_id: \"1000\"",
+            stub.url()
+        ))
+        .unwrap();
+        let mut pvoutput = PvOutput::new(&config).unwrap();
+        let now = chrono::Local::now().timestamp() as i32;
+        let start = now - now % 900 - 3600;
+
+        pvoutput.publish(&snapshot(start, 100, 1000));
+        // a second DTU can't share the system of the first one
+        pvoutput.publish(&HMSStateResponse {
+            dtu_sn: "456".into(),
+            ..snapshot(start, 100, 1000)
+        });
+        assert_eq!(pvoutput.intervals.len(), 1);
+
+        pvoutput.poll_result("dtu", false);
+        assert!(stub.requests().is_empty());
+        // the DTU stopped replying for an interval length, e.g. at sunset
+        pvoutput.intervals.get_mut("123").unwrap().last_update = Instant::now()
+            .checked_sub(Duration::from_secs(300))
+            .unwrap();
+        pvoutput.poll_result("dtu", false);
+        assert!(pvoutput.intervals.is_empty());
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers["x-pvoutput-systemid"], "1000");
+        assert!(statuses(&requests[0].body_text())[0].ends_with(",1000,100,,,,,25.00,231.00"));
+    }
+}
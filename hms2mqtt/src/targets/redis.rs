@@ -0,0 +1,461 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    targets::{
+        metric_filter::MetricFilter,
+        metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias,
+        template::{SnapshotVariable, Template},
+    },
+};
+
+fn default_port() -> u16 {
+    6379
+}
+
+fn default_stream() -> Template<SnapshotVariable> {
+    "hms:{{serial}}".parse().unwrap()
+}
+
+fn default_max_len() -> u64 {
+    10000
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RedisConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// ACL user, the default user is used if only a password is set
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Database selected after connecting
+    pub database: Option<u32>,
+    /// Key of the stream, e.g. `hms:{{serial}}` or `solar:{{alias}}`
+    #[serde(default = "default_stream")]
+    pub stream: Template<SnapshotVariable>,
+    /// Streams are trimmed to approximately this many entries
+    #[serde(default = "default_max_len")]
+    pub max_len: u64,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the added values
+    #[serde(default)]
+    pub filter: MetricFilter,
+    /// Seconds before connecting or writing is aborted
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// A reply of the server, nested replies are not needed for the used commands
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    /// The command failed, the connection remains usable
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+}
+
+impl Connection {
+    /// Sends a command as RESP array of bulk strings and reads the reply
+    fn command(&mut self, arguments: &[&str]) -> anyhow::Result<Reply> {
+        let mut request = format!("*{}\r\n", arguments.len());
+        for argument in arguments {
+            request.push_str(&format!("${}\r\n{argument}\r\n", argument.len()));
+        }
+        self.stream.write_all(request.as_bytes())?;
+        self.read_reply()
+    }
+
+    /// Sends a command that has to succeed to continue with the connection
+    fn expect_success(&mut self, arguments: &[&str]) -> anyhow::Result<Reply> {
+        match self.command(arguments)? {
+            Reply::Error(message) => bail!("{message}"),
+            reply => Ok(reply),
+        }
+    }
+
+    fn read_reply(&mut self) -> anyhow::Result<Reply> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            bail!("connection closed by the server");
+        }
+        let line = line.trim_end_matches("\r\n");
+        let (kind, content) = line.split_at(line.len().min(1));
+        match kind {
+            "+" => Ok(Reply::Status(content.to_string())),
+            "-" => Ok(Reply::Error(content.to_string())),
+            ":" => Ok(Reply::Integer(content.parse()?)),
+            "$" => match content.parse::<i64>()? {
+                length if length < 0 => Ok(Reply::Bulk(None)),
+                length => {
+                    let mut data = vec![0; length as usize + 2];
+                    self.reader.read_exact(&mut data)?;
+                    data.truncate(length as usize);
+                    Ok(Reply::Bulk(Some(
+                        String::from_utf8_lossy(&data).into_owned(),
+                    )))
+                }
+            },
+            _ => bail!("unexpected reply '{line}'"),
+        }
+    }
+}
+
+/// `Redis` appends every snapshot as JSON document to a Redis stream using `XADD`.
+///
+/// The connection is established lazily and re-established after failures with an increasing
+/// delay, so an unreachable server does not delay every poll by the connect timeout.
+pub struct Redis {
+    config: RedisConfig,
+    connection: Option<Connection>,
+    reconnect_delay: Duration,
+    next_connect: Instant,
+}
+
+impl Redis {
+    pub fn new(config: &RedisConfig) -> anyhow::Result<Self> {
+        if config.username.is_some() && config.password.is_none() {
+            bail!("username requires a password");
+        }
+        Ok(Self {
+            config: config.clone(),
+            connection: None,
+            reconnect_delay: MIN_RECONNECT_DELAY,
+            next_connect: Instant::now(),
+        })
+    }
+
+    fn ensure_connected(&mut self) {
+        if self.connection.is_some() || Instant::now() < self.next_connect {
+            return;
+        }
+        match self.connect() {
+            Ok(connection) => {
+                self.connection = Some(connection);
+                self.reconnect_delay = MIN_RECONNECT_DELAY;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to connect to Redis, retrying in {:?}: {e:#}",
+                    self.reconnect_delay
+                );
+                self.next_connect = Instant::now() + self.reconnect_delay;
+                self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+
+    fn connect(&self) -> anyhow::Result<Connection> {
+        let address = format!("{}:{}", self.config.host, self.config.port);
+        let timeout = Duration::from_secs(self.config.timeout);
+        let socket_address = address
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("could not resolve {address}"))?;
+        let stream = TcpStream::connect_timeout(&socket_address, timeout)
+            .with_context(|| format!("could not connect to {address}"))?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            stream,
+        };
+
+        if let Some(password) = &self.config.password {
+            match &self.config.username {
+                Some(username) => connection.expect_success(&["AUTH", username, password]),
+                None => connection.expect_success(&["AUTH", password]),
+            }
+            .context("authentication failed")?;
+        }
+        if let Some(database) = self.config.database {
+            connection
+                .expect_success(&["SELECT", &database.to_string()])
+                .with_context(|| format!("could not select database {database}"))?;
+        }
+        info!("Connected to Redis at {address}");
+        Ok(connection)
+    }
+
+    fn add(&mut self, arguments: &[&str]) -> anyhow::Result<Reply> {
+        // the connection may have been closed since the last snapshot, so reconnect once
+        for _ in 0..2 {
+            self.ensure_connected();
+            let Some(connection) = self.connection.as_mut() else {
+                break;
+            };
+            // I/O errors drop the connection, error replies of the server leave it usable
+            match connection.command(arguments) {
+                Ok(reply) => return Ok(reply),
+                Err(e) => {
+                    warn!("Lost connection to Redis: {e:#}");
+                    self.connection = None;
+                }
+            }
+        }
+        bail!("Redis is unreachable")
+    }
+}
+
+impl MetricPublisher for Redis {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        let alias = hms_state.get_alias(&self.config.serial_aliases);
+        let metrics: Vec<_> = hms_state
+            .get_metrics()
+            .into_iter()
+            .filter(|metric| {
+                self.config
+                    .filter
+                    .is_included(&hms_state.dtu_sn, alias, &metric.path())
+            })
+            .collect();
+        // fake inverters and some firmwares report no time
+        let timestamp = match hms_state.time {
+            time if time > 0 => time as u64,
+            _ => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        let (_, document) = hms_state
+            .get_json_documents("", &metrics, false)
+            .pop()
+            .unwrap_or_default();
+        let document = document.to_string();
+        let stream = self.config.stream.render(|variable| match variable {
+            SnapshotVariable::Serial => hms_state.dtu_sn.clone(),
+            SnapshotVariable::Alias => alias.to_string(),
+            SnapshotVariable::Timestamp => timestamp.to_string(),
+            SnapshotVariable::Json => document.clone(),
+            SnapshotVariable::Metric(path) => metrics
+                .iter()
+                .find(|metric| metric.path() == path)
+                .map_or("null".to_string(), |metric| metric.value.to_string()),
+        });
+
+        let max_len = self.config.max_len.to_string();
+        let timestamp = timestamp.to_string();
+        let alias = alias.to_string();
+        // `~` lets the server trim whole macro nodes, which is much cheaper than exact trimming
+        let arguments = [
+            "XADD",
+            &stream,
+            "MAXLEN",
+            "~",
+            &max_len,
+            "*",
+            "serial",
+            &hms_state.dtu_sn,
+            "alias",
+            &alias,
+            "timestamp",
+            &timestamp,
+            "snapshot",
+            &document,
+        ];
+        match self.add(&arguments) {
+            Ok(Reply::Bulk(Some(id))) => debug!("Added entry {id} to {stream}"),
+            Ok(Reply::Error(message)) => warn!("Redis rejected the entry for {stream}: {message}"),
+            Ok(reply) => warn!("Unexpected reply of Redis to XADD: {reply:?}"),
+            Err(e) => warn!("Failed to add snapshot to Redis stream {stream}: {e:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+        time::Duration,
+    };
+
+    use super::{Redis, RedisConfig};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, PortState},
+        targets::metric_publisher::MetricPublisher,
+    };
+
+    /// Reads a command sent as RESP array
+    fn read_command(reader: &mut impl BufRead) -> Vec<String> {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let count: usize = line.trim_end()[1..].parse().unwrap();
+        (0..count)
+            .map(|_| {
+                let mut length = String::new();
+                reader.read_line(&mut length).unwrap();
+                let mut argument = String::new();
+                reader.read_line(&mut argument).unwrap();
+                argument.trim_end_matches("\r\n").to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_xadd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config: RedisConfig = serde_yaml::from_str(&format!(
+            r#"
+            host: "127.0.0.1"
+            port: {}
+            password: "secret"
+            database: 2
+            stream: "solar:{{{{alias}}}}"
+            max_len: 500
+            serial_aliases:
+              - serial: "123"
+                alias: "roof"
+            filter:
+              include: ["current_power", "port/*/power"]
+            "#,
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut commands = Vec::new();
+            for reply in ["+OK\r\n", "+OK\r\n", "$15\r\n1700000000000-0\r\n"] {
+                commands.push(read_command(&mut reader));
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            commands
+        });
+
+        let mut redis = Redis::new(&config).unwrap();
+        redis.publish(&HMSStateResponse {
+            dtu_sn: "123".into(),
+            time: 1_700_000_000,
+            pv_current_power: 1234,
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_power: 617,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        assert!(redis.connection.is_some());
+
+        let commands = server.join().unwrap();
+        assert_eq!(commands[0], ["AUTH", "secret"]);
+        assert_eq!(commands[1], ["SELECT", "2"]);
+        assert_eq!(
+            commands[2][..12],
+            [
+                "XADD",
+                "solar:roof",
+                "MAXLEN",
+                "~",
+                "500",
+                "*",
+                "serial",
+                "123",
+                "alias",
+                "roof",
+                "timestamp",
+                "1700000000"
+            ]
+        );
+        assert_eq!(commands[2][12], "snapshot");
+        let document: serde_json::Value = serde_json::from_str(&commands[2][13]).unwrap();
+        assert_eq!(document["values"]["current_power"]["value"], 123.4);
+    }
+
+    #[test]
+    fn test_error_reply_keeps_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config: RedisConfig = serde_yaml::from_str(&format!(
+            "host: '127.0.0.1'\nport: {}",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            for reply in [
+                "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+                "$15\r\n1700000000000-0\r\n",
+            ] {
+                read_command(&mut reader);
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let mut redis = Redis::new(&config).unwrap();
+        let snapshot = HMSStateResponse {
+            dtu_sn: "123".into(),
+            ..Default::default()
+        };
+        redis.publish(&snapshot);
+        assert!(redis.connection.is_some());
+        // the server only accepts one connection
+        redis.publish(&snapshot);
+        assert!(redis.connection.is_some());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_after_closed_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config: RedisConfig = serde_yaml::from_str(&format!(
+            "host: '127.0.0.1'\nport: {}",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+
+        let server = thread::spawn(move || {
+            let mut commands = Vec::new();
+            // the first connection is closed after one entry, e.g. by a restart of the server
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                commands.push(read_command(&mut reader));
+                stream.write_all(b"$15\r\n1700000000000-0\r\n").unwrap();
+            }
+            commands
+        });
+
+        let mut redis = Redis::new(&config).unwrap();
+        let snapshot = HMSStateResponse {
+            dtu_sn: "123".into(),
+            ..Default::default()
+        };
+        redis.publish(&snapshot);
+        // wait for the server to close the first connection
+        thread::sleep(Duration::from_millis(100));
+        redis.publish(&snapshot);
+        assert!(redis.connection.is_some());
+        let commands = server.join().unwrap();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[1][0], "XADD");
+    }
+}
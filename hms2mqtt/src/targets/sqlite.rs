@@ -0,0 +1,508 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use chrono::NaiveDate;
+use log::{debug, info, warn};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::Deserialize;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    sources::metric::MetricLevel,
+    targets::{
+        metric_filter::MetricFilter, metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias,
+    },
+};
+
+fn default_raw_retention_days() -> u32 {
+    7
+}
+
+fn default_downsample_interval() -> u32 {
+    900
+}
+
+/// Seconds between two runs of the downsampling
+const MAINTENANCE_INTERVAL: i64 = 3600;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS dtu (
+        id INTEGER PRIMARY KEY,
+        serial TEXT NOT NULL UNIQUE,
+        alias TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS metric (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        unit TEXT
+    );
+    -- inverter and port are NULL for values of the DTU, port is NULL for values of an inverter
+    CREATE TABLE IF NOT EXISTS reading (
+        dtu_id INTEGER NOT NULL REFERENCES dtu(id),
+        inverter INTEGER,
+        port INTEGER,
+        metric_id INTEGER NOT NULL REFERENCES metric(id),
+        ts INTEGER NOT NULL,
+        value REAL NOT NULL,
+        -- seconds summarized by a downsampled reading, 0 for raw readings
+        resolution INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS reading_by_time ON reading (dtu_id, metric_id, ts);
+";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SqliteConfig {
+    /// Database file, created if missing
+    pub file: PathBuf,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the stored values
+    #[serde(default)]
+    pub filter: MetricFilter,
+    /// Days raw readings are kept before they are downsampled
+    #[serde(default = "default_raw_retention_days")]
+    pub raw_retention_days: u32,
+    /// Seconds summarized by one downsampled reading. Power and other momentary values are
+    /// averaged, energy counters keep their maximum.
+    #[serde(default = "default_downsample_interval")]
+    pub downsample_interval: u32,
+    /// Days readings are kept at all, forever if not set
+    pub retention_days: Option<u32>,
+}
+
+/// `Sqlite` stores every snapshot in a local database to query the history later on.
+pub struct Sqlite {
+    config: SqliteConfig,
+    connection: Connection,
+    dtu_ids: HashMap<String, i64>,
+    metric_ids: HashMap<&'static str, i64>,
+    last_maintenance: i64,
+}
+
+impl Sqlite {
+    pub fn new(config: &SqliteConfig) -> anyhow::Result<Self> {
+        if config.downsample_interval == 0 {
+            bail!("downsample_interval has to be positive");
+        }
+        let connection = Connection::open(&config.file)
+            .with_context(|| format!("could not open database {:?}", config.file))?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            config: config.clone(),
+            connection,
+            dtu_ids: HashMap::new(),
+            metric_ids: HashMap::new(),
+            last_maintenance: 0,
+        })
+    }
+
+    fn dtu_id(&mut self, serial: &str, alias: &str) -> anyhow::Result<i64> {
+        if let Some(id) = self.dtu_ids.get(serial) {
+            return Ok(*id);
+        }
+        // the alias may have changed since the DTU was stored
+        let id = self.connection.query_row(
+            "INSERT INTO dtu (serial, alias) VALUES (?1, ?2)
+             ON CONFLICT (serial) DO UPDATE SET alias = excluded.alias
+             RETURNING id",
+            params![serial, alias],
+            |row| row.get(0),
+        )?;
+        self.dtu_ids.insert(serial.to_string(), id);
+        Ok(id)
+    }
+
+    fn metric_id(&mut self, name: &'static str, unit: Option<&str>) -> anyhow::Result<i64> {
+        if let Some(id) = self.metric_ids.get(name) {
+            return Ok(*id);
+        }
+        let id = self.connection.query_row(
+            "INSERT INTO metric (name, unit) VALUES (?1, ?2)
+             ON CONFLICT (name) DO UPDATE SET unit = excluded.unit
+             RETURNING id",
+            params![name, unit],
+            |row| row.get(0),
+        )?;
+        self.metric_ids.insert(name, id);
+        Ok(id)
+    }
+
+    fn store(&mut self, hms_state: &HMSStateResponse) -> anyhow::Result<i64> {
+        let alias = hms_state.get_alias(&self.config.serial_aliases).to_string();
+        // fake inverters and some firmwares report no time
+        let ts = match hms_state.time {
+            time if time > 0 => time as i64,
+            _ => now(),
+        };
+        let dtu_id = self.dtu_id(&hms_state.dtu_sn, &alias)?;
+        let mut readings = Vec::new();
+        for metric in hms_state.get_metrics() {
+            if self
+                .config
+                .filter
+                .is_included(&hms_state.dtu_sn, &alias, &metric.path())
+            {
+                let metric_id = self.metric_id(metric.name, metric.unit)?;
+                let (inverter, port) = match metric.level {
+                    MetricLevel::Dtu => (None, None),
+                    MetricLevel::Inverter(id) => (Some(id), None),
+                    MetricLevel::Port(port) => (None, Some(port as i64)),
+                };
+                readings.push((inverter, port, metric_id, metric.value));
+            }
+        }
+
+        let transaction = self.connection.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT INTO reading (dtu_id, inverter, port, metric_id, ts, value)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for (inverter, port, metric_id, value) in readings {
+                insert.execute(params![dtu_id, inverter, port, metric_id, ts, value])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(ts)
+    }
+
+    /// Downsamples old raw readings and removes expired ones
+    fn maintain(&mut self, ts: i64) -> anyhow::Result<()> {
+        let interval = self.config.downsample_interval as i64;
+        // only complete intervals are downsampled
+        let raw_cutoff =
+            (ts - self.config.raw_retention_days as i64 * 86_400) / interval * interval;
+
+        let transaction = self.connection.transaction()?;
+        let downsampled = transaction.execute(
+            "INSERT INTO reading (dtu_id, inverter, port, metric_id, ts, value, resolution)
+             SELECT reading.dtu_id, reading.inverter, reading.port, reading.metric_id,
+                 reading.ts / ?1 * ?1,
+                 CASE WHEN metric.unit IN ('Wh', 'kWh') THEN MAX(reading.value)
+                     ELSE AVG(reading.value) END,
+                 ?1
+             FROM reading JOIN metric ON metric.id = reading.metric_id
+             WHERE reading.resolution = 0 AND reading.ts < ?2
+             GROUP BY reading.dtu_id, reading.inverter, reading.port, reading.metric_id,
+                 reading.ts / ?1",
+            params![interval, raw_cutoff],
+        )?;
+        transaction.execute(
+            "DELETE FROM reading WHERE resolution = 0 AND ts < ?1",
+            params![raw_cutoff],
+        )?;
+        let expired = match self.config.retention_days {
+            Some(days) => transaction.execute(
+                "DELETE FROM reading WHERE ts < ?1",
+                params![ts - days as i64 * 86_400],
+            )?,
+            None => 0,
+        };
+        transaction.commit()?;
+        if downsampled > 0 || expired > 0 {
+            info!("Downsampled to {downsampled} readings, removed {expired} expired readings");
+        }
+        Ok(())
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+impl MetricPublisher for Sqlite {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        let ts = match self.store(hms_state) {
+            Ok(ts) => ts,
+            Err(e) => {
+                warn!("Failed to store values of {}: {e}", hms_state.dtu_sn);
+                return;
+            }
+        };
+        debug!("Stored values of {}", hms_state.dtu_sn);
+        if ts - self.last_maintenance >= MAINTENANCE_INTERVAL {
+            self.last_maintenance = ts;
+            if let Err(e) = self.maintain(ts) {
+                warn!("Failed to downsample the history: {e}");
+            }
+        }
+    }
+}
+
+/// Kind of history to show
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryKind {
+    /// Energy of every day
+    Daily,
+    /// Energy of every month
+    Monthly,
+    /// Average power per `interval`
+    Power,
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryQuery {
+    pub kind: HistoryKind,
+    /// Serial or alias of the DTU, may be omitted if the database holds a single DTU
+    pub dtu: Option<String>,
+    /// Restricts the history to one port instead of the whole DTU
+    pub port: Option<i32>,
+    /// First day included, in local time
+    pub from: NaiveDate,
+    /// Last day included, in local time
+    pub to: NaiveDate,
+    /// Seconds summarized by a row of the power curve
+    pub interval: u32,
+}
+
+/// Result of a history query with a label and a value per row
+#[derive(Debug, Clone, PartialEq)]
+pub struct History {
+    pub columns: [&'static str; 2],
+    pub rows: Vec<(String, f64)>,
+}
+
+impl History {
+    pub fn to_table(&self) -> String {
+        let width = self
+            .rows
+            .iter()
+            .map(|(label, _)| label.len())
+            .chain([self.columns[0].len()])
+            .max()
+            .unwrap_or_default();
+        let mut table = format!("{:<width$}  {:>12}\n", self.columns[0], self.columns[1]);
+        for (label, value) in &self.rows {
+            let _ = writeln!(table, "{label:<width$}  {value:>12.1}");
+        }
+        table
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("{},{}\n", self.columns[0], self.columns[1]);
+        for (label, value) in &self.rows {
+            let _ = writeln!(csv, "{label},{value}");
+        }
+        csv
+    }
+}
+
+/// Queries the history stored by a [`Sqlite`] target
+pub fn query_history(file: &Path, query: &HistoryQuery) -> anyhow::Result<History> {
+    let connection = Connection::open_with_flags(file, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("could not open database {file:?}"))?;
+    let dtu_id = find_dtu(&connection, query.dtu.as_deref())?;
+
+    let power = match query.port {
+        Some(_) => "power",
+        None => "current_power",
+    };
+    // DTU values have no port, so comparing with NULL selects them
+    let level = "reading.inverter IS NULL AND reading.port IS ?3";
+    let from = query.from.to_string();
+    let to = query.to.to_string();
+
+    let (columns, sql, metric) = match query.kind {
+        HistoryKind::Daily => (
+            ["day", "energy [Wh]"],
+            format!(
+                "SELECT date(ts, 'unixepoch', 'localtime') AS day, MAX(value)
+                 FROM reading JOIN metric ON metric.id = reading.metric_id
+                 WHERE dtu_id = ?1 AND metric.name = ?2 AND {level} AND day BETWEEN ?4 AND ?5
+                 GROUP BY day ORDER BY day"
+            ),
+            "daily_yield",
+        ),
+        HistoryKind::Monthly => (
+            ["month", "energy [kWh]"],
+            format!(
+                "SELECT substr(day, 1, 7) AS month, SUM(energy) / 1000 FROM (
+                     SELECT date(ts, 'unixepoch', 'localtime') AS day, MAX(value) AS energy
+                     FROM reading JOIN metric ON metric.id = reading.metric_id
+                     WHERE dtu_id = ?1 AND metric.name = ?2 AND {level}
+                         AND day BETWEEN ?4 AND ?5
+                     GROUP BY day
+                 ) GROUP BY month ORDER BY month"
+            ),
+            "daily_yield",
+        ),
+        HistoryKind::Power => (
+            ["time", "power [W]"],
+            format!(
+                "SELECT datetime(ts / ?6 * ?6, 'unixepoch', 'localtime') AS time, AVG(value)
+                 FROM reading JOIN metric ON metric.id = reading.metric_id
+                 WHERE dtu_id = ?1 AND metric.name = ?2 AND {level}
+                     AND date(ts, 'unixepoch', 'localtime') BETWEEN ?4 AND ?5
+                 GROUP BY ts / ?6 ORDER BY time"
+            ),
+            power,
+        ),
+    };
+
+    let interval = query.interval.max(1);
+    let parameters = params![dtu_id, metric, query.port, from, to, interval];
+    // unused parameters are not allowed
+    let parameters = match query.kind {
+        HistoryKind::Power => parameters,
+        _ => &parameters[..5],
+    };
+    let mut statement = connection.prepare(&sql)?;
+    let rows = statement
+        .query_map(parameters, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    Ok(History { columns, rows })
+}
+
+fn find_dtu(connection: &Connection, dtu: Option<&str>) -> anyhow::Result<i64> {
+    if let Some(dtu) = dtu {
+        return connection
+            .query_row(
+                "SELECT id FROM dtu WHERE serial = ?1 OR alias = ?1",
+                params![dtu],
+                |row| row.get(0),
+            )
+            .optional()?
+            .with_context(|| format!("no history of DTU {dtu}"));
+    }
+    let mut statement = connection.prepare("SELECT id, alias FROM dtu")?;
+    let dtus: Vec<(i64, String)> = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    match dtus.as_slice() {
+        [] => bail!("the database holds no history yet"),
+        [(id, _)] => Ok(*id),
+        _ => {
+            let aliases: Vec<_> = dtus.iter().map(|(_, alias)| alias.as_str()).collect();
+            bail!("select one of the DTUs {}", aliases.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use chrono::{DateTime, Local};
+
+    use super::{query_history, HistoryKind, HistoryQuery, Sqlite, SqliteConfig};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, PortState},
+        targets::metric_publisher::MetricPublisher,
+    };
+
+    fn snapshot(time: i64, daily_yield: i32, power: i32) -> HMSStateResponse {
+        HMSStateResponse {
+            dtu_sn: "123".into(),
+            time: time as i32,
+            pv_current_power: power,
+            pv_daily_yield: daily_yield,
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_power: power / 2,
+                pv_daily_yield: daily_yield / 2,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn local(time: i64) -> DateTime<Local> {
+        DateTime::<Local>::from(UNIX_EPOCH + Duration::from_secs(time as u64))
+    }
+
+    #[test]
+    fn test_store_downsample_and_query() {
+        let file = std::env::temp_dir().join(format!("history_{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&file);
+        let config: SqliteConfig = serde_yaml::from_str(&format!(
+            r#"
+            file: {file:?}
+            raw_retention_days: 1
+            downsample_interval: 3600
+            serial_aliases:
+              - serial: "123"
+                alias: "roof"
+            "#
+        ))
+        .unwrap();
+        let mut sqlite = Sqlite::new(&config).unwrap();
+
+        // noon UTC, so the days are different in every time zone of the test machine
+        let first_day = 1_699_963_200;
+        let second_day = first_day + 86_400;
+        let third_day = second_day + 86_400;
+        for (minute, power) in [(0, 1000), (20, 2000), (40, 3000)] {
+            sqlite.publish(&snapshot(
+                first_day + minute * 60,
+                100 + minute as i32,
+                power,
+            ));
+        }
+        sqlite.publish(&snapshot(second_day, 500, 4000));
+        sqlite.publish(&snapshot(third_day, 700, 5000));
+
+        // the first day is downsampled to a single reading per metric
+        let raw: i64 = sqlite
+            .connection
+            .query_row(
+                "SELECT COUNT(*) FROM reading WHERE resolution = 0 AND ts < ?1",
+                [first_day + 86_400],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(raw, 0);
+
+        let mut query = HistoryQuery {
+            kind: HistoryKind::Daily,
+            dtu: Some("roof".into()),
+            port: None,
+            from: local(first_day).date_naive(),
+            to: local(third_day).date_naive(),
+            interval: 3600,
+        };
+        let history = query_history(&file, &query).unwrap();
+        let days: Vec<_> = [first_day, second_day, third_day]
+            .into_iter()
+            .map(|time| local(time).date_naive().to_string())
+            .collect();
+        assert_eq!(
+            history.rows,
+            [
+                (days[0].clone(), 140.),
+                (days[1].clone(), 500.),
+                (days[2].clone(), 700.)
+            ]
+        );
+
+        query.port = Some(1);
+        query.dtu = None;
+        let history = query_history(&file, &query).unwrap();
+        assert_eq!(history.rows[1], (days[1].clone(), 250.));
+
+        query.port = None;
+        query.kind = HistoryKind::Power;
+        query.to = query.from;
+        let history = query_history(&file, &query).unwrap();
+        assert_eq!(history.rows.len(), 1);
+        assert_eq!(history.rows[0].1, 200.);
+        assert!(history.to_csv().starts_with("time,power [W]\n"));
+
+        query.kind = HistoryKind::Monthly;
+        query.to = local(third_day).date_naive();
+        let history = query_history(&file, &query).unwrap();
+        let total: f64 = history.rows.iter().map(|(_, energy)| energy).sum();
+        assert!((total - 1.34).abs() < 1e-9);
+
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use anyhow::Context;
+use log::warn;
+use serde::Deserialize;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    targets::{
+        metric_filter::MetricFilter, metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias,
+    },
+};
+
+fn default_port() -> u16 {
+    8125
+}
+
+fn default_prefix() -> String {
+    "hms".into()
+}
+
+fn default_max_packet_size() -> usize {
+    // fits into a single ethernet frame
+    1432
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StatsdConfig {
+    /// Host of the StatsD daemon
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// First element of every gauge, e.g. `hms.dtu.<serial or alias>.port.1.power`
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the sent values
+    #[serde(default)]
+    pub filter: MetricFilter,
+    /// Gauges are combined into packets of at most this many bytes
+    #[serde(default = "default_max_packet_size")]
+    pub max_packet_size: usize,
+}
+
+/// `Statsd` sends every value as gauge over UDP.
+pub struct Statsd {
+    config: StatsdConfig,
+    socket: UdpSocket,
+}
+
+impl Statsd {
+    pub fn new(config: &StatsdConfig) -> anyhow::Result<Self> {
+        let address = (config.host.as_str(), config.port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .with_context(|| format!("could not resolve {}", config.host))?;
+        // the local address has to be of the same family, e.g. on IPv6-only hosts
+        let local_address: SocketAddr = match address {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local_address)?;
+        socket.connect(address)?;
+        Ok(Self {
+            config: config.clone(),
+            socket,
+        })
+    }
+
+    fn send(&self, packet: &str) {
+        if let Err(e) = self.socket.send(packet.as_bytes()) {
+            warn!("Failed to send to StatsD: {e}");
+        }
+    }
+}
+
+impl MetricPublisher for Statsd {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        let mut packet = String::new();
+        for (path, value) in hms_state.get_dotted_metrics(
+            &self.config.prefix,
+            &self.config.serial_aliases,
+            &self.config.filter,
+        ) {
+            // a signed gauge changes the value instead of setting it, so reset it first
+            let gauge = if value < 0. {
+                format!("{path}:0|g\n{path}:{value}|g")
+            } else {
+                format!("{path}:{value}|g")
+            };
+            if !packet.is_empty() && packet.len() + gauge.len() + 1 > self.config.max_packet_size {
+                self.send(&packet);
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&gauge);
+        }
+        if !packet.is_empty() {
+            self.send(&packet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::UdpSocket, time::Duration};
+
+    use super::{Statsd, StatsdConfig};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, PortState},
+        targets::metric_publisher::MetricPublisher,
+    };
+
+    #[test]
+    fn test_gauges() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let config: StatsdConfig = serde_yaml::from_str(&format!(
+            r#"
+            host: "127.0.0.1"
+            port: {}
+            max_packet_size: 40
+            filter:
+              include: ["current_power", "port/*/power"]
+            "#,
+            receiver.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        let mut statsd = Statsd::new(&config).unwrap();
+        statsd.publish(&HMSStateResponse {
+            dtu_sn: "123".into(),
+            pv_current_power: 1234,
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_power: 617,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+
+        let mut buffer = [0; 1500];
+        let mut packets = Vec::new();
+        for _ in 0..2 {
+            let length = receiver.recv(&mut buffer).unwrap();
+            packets.push(String::from_utf8_lossy(&buffer[..length]).into_owned());
+        }
+        assert_eq!(
+            packets,
+            [
+                "hms.dtu.123.current_power:123.4|g",
+                "hms.dtu.123.port.1.power:61.7|g"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let receiver = UdpSocket::bind("[::1]:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let config: StatsdConfig = serde_yaml::from_str(&format!(
+            "host: '::1'\nport: {}\nfilter: {{include: [current_power]}}",
+            receiver.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        let mut statsd = Statsd::new(&config).unwrap();
+        statsd.publish(&HMSStateResponse {
+            dtu_sn: "123".into(),
+            ..Default::default()
+        });
+
+        let mut buffer = [0; 1500];
+        let length = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"hms.dtu.123.current_power:0|g");
+    }
+}
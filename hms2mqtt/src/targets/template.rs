@@ -0,0 +1,231 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    sources::metric::{Metric, MetricLevel},
+};
+
+/// A text with `{{ variable }}` placeholders, e.g. `{{base_topic}}/{{alias}}/{{metric}}`.
+///
+/// The variables are checked when parsing, so invalid templates are reported on startup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template<V> {
+    parts: Vec<Part<V>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part<V> {
+    Text(String),
+    Variable(V),
+}
+
+impl<V: FromStr<Err = anyhow::Error>> FromStr for Template<V> {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}") else {
+                bail!("unclosed '{{{{' in template '{template}'");
+            };
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let name = rest[start + 2..start + end].trim();
+            let variable = name
+                .parse()
+                .map_err(|e| anyhow!("invalid template '{template}': {e}"))?;
+            parts.push(Part::Variable(variable));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl<'de, V: FromStr<Err = anyhow::Error>> Deserialize<'de> for Template<V> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl<V: Clone> Template<V> {
+    /// Replaces every variable with the value returned by `lookup`
+    pub fn render<T: Display>(&self, lookup: impl Fn(V) -> T) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Variable(variable) => lookup(variable.clone()).to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Variables available in templates rendered for a single metric
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricVariable {
+    /// Configured base topic
+    BaseTopic,
+    /// Serial of the DTU
+    Serial,
+    /// Alias of the DTU or its serial if there is none
+    Alias,
+    /// `dtu`, `inverter` or `port`
+    Component,
+    /// Inverter id, empty for other components
+    Inverter,
+    /// Port number, empty for other components
+    Port,
+    /// Name of the metric, e.g. `power`
+    Metric,
+    /// Path of the metric below the DTU, e.g. `port/1/power`
+    Path,
+    Value,
+    /// Unit of the value, empty if it has none
+    Unit,
+    /// Inverter time as unix timestamp
+    Timestamp,
+}
+
+const METRIC_VARIABLES: [(&str, MetricVariable); 11] = [
+    ("base_topic", MetricVariable::BaseTopic),
+    ("serial", MetricVariable::Serial),
+    ("alias", MetricVariable::Alias),
+    ("component", MetricVariable::Component),
+    ("inverter", MetricVariable::Inverter),
+    ("port", MetricVariable::Port),
+    ("metric", MetricVariable::Metric),
+    ("path", MetricVariable::Path),
+    ("value", MetricVariable::Value),
+    ("unit", MetricVariable::Unit),
+    ("timestamp", MetricVariable::Timestamp),
+];
+
+impl FromStr for MetricVariable {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        METRIC_VARIABLES
+            .iter()
+            .find(|(variable_name, _)| *variable_name == name)
+            .map(|(_, variable)| *variable)
+            .ok_or_else(|| {
+                let names: Vec<_> = METRIC_VARIABLES.iter().map(|(name, _)| *name).collect();
+                anyhow!(
+                    "unknown variable '{name}', expected one of {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl MetricVariable {
+    pub fn get_value(
+        self,
+        base_topic: &str,
+        alias: &str,
+        hms_state: &HMSStateResponse,
+        metric: &Metric,
+    ) -> String {
+        match (self, metric.level) {
+            (Self::BaseTopic, _) => base_topic.to_string(),
+            (Self::Serial, _) => hms_state.dtu_sn.clone(),
+            (Self::Alias, _) => alias.to_string(),
+            (Self::Component, MetricLevel::Dtu) => "dtu".to_string(),
+            (Self::Component, MetricLevel::Inverter(_)) => "inverter".to_string(),
+            (Self::Component, MetricLevel::Port(_)) => "port".to_string(),
+            (Self::Inverter, MetricLevel::Inverter(id)) => id.to_string(),
+            (Self::Port, MetricLevel::Port(port)) => port.to_string(),
+            (Self::Inverter | Self::Port, _) => String::new(),
+            (Self::Metric, _) => metric.name.to_string(),
+            (Self::Path, _) => metric.path(),
+            (Self::Value, _) => (metric.value as f32).to_string(),
+            (Self::Unit, _) => metric.unit.unwrap_or_default().to_string(),
+            (Self::Timestamp, _) => hms_state.time.to_string(),
+        }
+    }
+}
+
+/// Variables available in templates rendered for a whole snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotVariable {
+    /// Serial of the DTU
+    Serial,
+    /// Alias of the DTU or its serial if there is none
+    Alias,
+    /// Inverter time as unix timestamp
+    Timestamp,
+    /// All values as JSON document
+    Json,
+    /// Value of the metric with the given path, e.g. `metric:port/1/power`, `null` if missing
+    Metric(String),
+}
+
+impl FromStr for SnapshotVariable {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "serial" => Ok(Self::Serial),
+            "alias" => Ok(Self::Alias),
+            "timestamp" => Ok(Self::Timestamp),
+            "json" => Ok(Self::Json),
+            _ => match name.strip_prefix("metric:") {
+                Some(path) => Ok(Self::Metric(path.trim().to_string())),
+                None => bail!(
+                    "unknown variable '{name}', expected one of serial, alias, timestamp, json, metric:<path>"
+                ),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MetricVariable, SnapshotVariable, Template};
+
+    #[test]
+    fn test_parse_and_render() {
+        let template: Template<MetricVariable> =
+            r#"{"value": {{ value }}, "unit": "{{unit}}"}"#.parse().unwrap();
+        let rendered = template.render(|variable| match variable {
+            MetricVariable::Value => "12.5",
+            MetricVariable::Unit => "W",
+            _ => unreachable!(),
+        });
+        assert_eq!(rendered, r#"{"value": 12.5, "unit": "W"}"#);
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        let error = "{{base_topic}}/{{serail}}"
+            .parse::<Template<MetricVariable>>()
+            .unwrap_err();
+        assert!(error.to_string().contains("unknown variable 'serail'"));
+        assert!("{{base_topic".parse::<Template<MetricVariable>>().is_err());
+    }
+
+    #[test]
+    fn test_snapshot_variables() {
+        let template: Template<SnapshotVariable> =
+            r#"{"power": {{ metric:port/1/power }}}"#.parse().unwrap();
+        let rendered = template.render(|variable| match variable {
+            SnapshotVariable::Metric(path) => path,
+            _ => unreachable!(),
+        });
+        assert_eq!(rendered, r#"{"power": port/1/power}"#);
+        assert!("{{metric}}".parse::<Template<SnapshotVariable>>().is_err());
+    }
+}
@@ -0,0 +1,359 @@
+use std::{collections::HashMap, fmt::Write as _, thread, time::Duration};
+
+use anyhow::bail;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use hmac::{Hmac, KeyInit, Mac};
+use log::{debug, warn};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{
+    protos::hoymiles::RealData::HMSStateResponse,
+    sources::metric::Metric,
+    targets::{
+        http_retry::{send_with_retries, SendError},
+        metric_filter::MetricFilter,
+        metric_publisher::MetricPublisher,
+        mqtt::mqtt_config::deserialize_alias,
+        template::{SnapshotVariable, Template},
+    },
+};
+
+fn default_content_type() -> String {
+    "application/json".into()
+}
+
+fn default_signature_header() -> String {
+    "X-Signature-256".into()
+}
+
+fn default_max_retries() -> u32 {
+    1
+}
+
+fn default_retry_interval() -> u64 {
+    1000
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookConfig {
+    /// Every snapshot is posted to each of these URLs, logged by their position as `url #<n>`
+    pub urls: Vec<String>,
+    /// Additional request headers
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// User for basic authentication
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Token for bearer authentication
+    pub bearer_token: Option<String>,
+    /// Body of the requests, the JSON document of the snapshot if not set. `serial` and `alias`
+    /// are escaped as JSON strings if the content type contains `json`.
+    pub body_template: Option<Template<SnapshotVariable>>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// Signs the body with HMAC-SHA256, the signature is sent as `sha256=<hex>`
+    pub hmac_secret: Option<String>,
+    /// Header carrying the signature
+    #[serde(default = "default_signature_header")]
+    pub signature_header: String,
+    /// Maps serials to an alias
+    #[serde(deserialize_with = "deserialize_alias", default)]
+    pub serial_aliases: HashMap<String, String>,
+    /// Selects the sent values
+    #[serde(default)]
+    pub filter: MetricFilter,
+    /// Retries of a failed request before the snapshot is dropped. The URLs are posted to in
+    /// parallel, but polling waits for the slowest one, so an unreachable URL delays it by up to
+    /// `(max_retries + 1) * timeout` plus the retry delays.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Milliseconds before the first retry, doubled for every further retry
+    #[serde(default = "default_retry_interval")]
+    pub retry_interval: u64,
+    /// Seconds before a request is aborted
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// `Webhook` posts every snapshot to the configured URLs.
+pub struct Webhook {
+    config: WebhookConfig,
+    agent: ureq::Agent,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig) -> anyhow::Result<Self> {
+        if config.urls.is_empty() {
+            bail!("no webhook URLs configured");
+        }
+        if config.username.is_some() && config.bearer_token.is_some() {
+            bail!("username and bearer_token are mutually exclusive");
+        }
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout))
+            .build();
+        Ok(Self {
+            config: config.clone(),
+            agent,
+        })
+    }
+
+    fn get_body(&self, hms_state: &HMSStateResponse, alias: &str, metrics: &[Metric]) -> String {
+        let document = || {
+            let (_, mut document) = hms_state
+                .get_json_documents("", metrics, false)
+                .pop()
+                .unwrap_or_default();
+            document["alias"] = alias.into();
+            document
+        };
+        let json = self.config.content_type.contains("json");
+        // the template provides the quotes, e.g. `{"alias": "{{alias}}"}`
+        let string = |value: &str| {
+            if json {
+                let quoted = serde_json::Value::from(value).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            } else {
+                value.to_string()
+            }
+        };
+        match &self.config.body_template {
+            Some(template) => template.render(|variable| match variable {
+                SnapshotVariable::Serial => string(&hms_state.dtu_sn),
+                SnapshotVariable::Alias => string(alias),
+                SnapshotVariable::Timestamp => hms_state.time.to_string(),
+                SnapshotVariable::Json => document().to_string(),
+                SnapshotVariable::Metric(path) => metrics
+                    .iter()
+                    .find(|metric| metric.path() == path)
+                    .map_or("null".to_string(), |metric| metric.value.to_string()),
+            }),
+            None => document().to_string(),
+        }
+    }
+
+    fn post(&self, url: &str, body: &str) -> Result<(), SendError> {
+        let config = &self.config;
+        let mut request = self
+            .agent
+            .post(url)
+            .set("Content-Type", &config.content_type);
+        for (name, value) in &config.headers {
+            request = request.set(name, value);
+        }
+        if let Some(username) = &config.username {
+            let credentials = format!(
+                "{username}:{}",
+                config.password.as_deref().unwrap_or_default()
+            );
+            request = request.set(
+                "Authorization",
+                &format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+            );
+        }
+        if let Some(token) = &config.bearer_token {
+            request = request.set("Authorization", &format!("Bearer {token}"));
+        }
+        if let Some(secret) = &config.hmac_secret {
+            request = request.set(&config.signature_header, &sign(secret, body));
+        }
+        request.send_string(body)?;
+        Ok(())
+    }
+}
+
+/// Returns the HMAC-SHA256 signature of `body` as `sha256=<hex>`
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    let mut signature = String::from("sha256=");
+    for byte in mac.finalize().into_bytes() {
+        let _ = write!(signature, "{byte:02x}");
+    }
+    signature
+}
+
+impl MetricPublisher for Webhook {
+    fn publish(&mut self, hms_state: &HMSStateResponse) {
+        let alias = hms_state.get_alias(&self.config.serial_aliases);
+        let metrics: Vec<_> = hms_state
+            .get_metrics()
+            .into_iter()
+            .filter(|metric| {
+                self.config
+                    .filter
+                    .is_included(&hms_state.dtu_sn, alias, &metric.path())
+            })
+            .collect();
+        let body = self.get_body(hms_state, alias, &metrics);
+
+        // an unreachable URL must not delay the others
+        let webhook = &*self;
+        thread::scope(|scope| {
+            for (index, url) in webhook.config.urls.iter().enumerate() {
+                let body = &body;
+                scope.spawn(move || {
+                    // webhook URLs often contain secrets, so only their position is logged
+                    let label = format!("url #{}", index + 1);
+                    let result = send_with_retries(
+                        &label,
+                        webhook.config.max_retries,
+                        webhook.config.retry_interval,
+                        || webhook.post(url, body),
+                    );
+                    match result {
+                        Ok(()) => debug!("Posted snapshot of {} to {label}", hms_state.dtu_sn),
+                        Err(SendError::Retryable(message) | SendError::Rejected(message)) => {
+                            warn!("Dropping snapshot for {label}: {message}")
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::TcpListener,
+        time::{Duration, Instant},
+    };
+
+    use super::{sign, Webhook, WebhookConfig};
+    use crate::{
+        protos::hoymiles::RealData::{HMSStateResponse, PortState},
+        targets::{http_stub::HttpStub, metric_publisher::MetricPublisher},
+    };
+
+    fn snapshot() -> HMSStateResponse {
+        HMSStateResponse {
+            dtu_sn: "123".into(),
+            time: 1_700_000_000,
+            pv_current_power: 1234,
+            port_state: vec![PortState {
+                pv_port: 1,
+                pv_power: 617,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_post_json_with_retry_and_signature() {
+        let stub = HttpStub::start(vec![503, 200]);
+        let config: WebhookConfig = serde_yaml::from_str(&format!(
+            r#"
+            urls: ["{}/hook"]
+            bearer_token: "token"
+            headers: {{X-Source: hms}}
+            hmac_secret: "secret"
+            retry_interval: 1
+            filter:
+              include: ["current_power", "port/*/power"]
+            "#,
+            stub.url()
+        ))
+        .unwrap();
+        let mut webhook = Webhook::new(&config).unwrap();
+        webhook.publish(&snapshot());
+
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 2);
+        let request = &requests[1];
+        assert_eq!(request.path, "/hook");
+        assert_eq!(request.headers["authorization"], "Bearer token");
+        assert_eq!(request.headers["x-source"], "hms");
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(
+            request.headers["x-signature-256"],
+            sign("secret", &request.body_text())
+        );
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["dtu_sn"], "123");
+        assert_eq!(body["alias"], "123");
+        assert_eq!(body["values"]["current_power"]["value"], 123.4);
+        assert_eq!(body["ports"]["1"]["power"]["value"], 61.7);
+    }
+
+    #[test]
+    fn test_post_template_with_basic_auth() {
+        let stub = HttpStub::start(vec![200]);
+        let config: WebhookConfig = serde_yaml::from_str(&format!(
+            r#"
+            urls: ["{}"]
+            username: "user"
+            password: "pass"
+            content_type: "text/plain"
+            body_template: "{{{{alias}}}} {{{{metric:port/1/power}}}} {{{{metric:port/2/power}}}}"
+            serial_aliases:
+              - serial: "123"
+                alias: "roof"
+            "#,
+            stub.url()
+        ))
+        .unwrap();
+        let mut webhook = Webhook::new(&config).unwrap();
+        webhook.publish(&snapshot());
+
+        let request = &stub.requests()[0];
+        assert_eq!(request.headers["authorization"], "Basic dXNlcjpwYXNz");
+        assert_eq!(request.body_text(), "roof 61.7 null");
+        // a known test vector of RFC 4231
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_json_template_escapes_strings() {
+        let stub = HttpStub::start(vec![200]);
+        let config: WebhookConfig = serde_yaml::from_str(&format!(
+            r#"
+            urls: ["{}"]
+            body_template: '{{"name": "{{{{alias}}}}", "power": {{{{metric:current_power}}}}}}'
+            serial_aliases:
+              - serial: "123"
+                alias: 'my "roof"\east'
+            "#,
+            stub.url()
+        ))
+        .unwrap();
+        let mut webhook = Webhook::new(&config).unwrap();
+        webhook.publish(&snapshot());
+
+        let body: serde_json::Value = serde_json::from_slice(&stub.requests()[0].body).unwrap();
+        assert_eq!(body["name"], r#"my "roof"\east"#);
+        assert_eq!(body["power"], 123.4);
+    }
+
+    #[test]
+    fn test_unreachable_url_does_not_delay_others() {
+        let stub = HttpStub::start(vec![200]);
+        // accepts connections but never answers
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config: WebhookConfig = serde_yaml::from_str(&format!(
+            r#"
+            urls: ["http://{}", "{}"]
+            max_retries: 0
+            timeout: 1
+            "#,
+            silent.local_addr().unwrap(),
+            stub.url()
+        ))
+        .unwrap();
+        let mut webhook = Webhook::new(&config).unwrap();
+        let start = Instant::now();
+        webhook.publish(&snapshot());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(stub.requests().len(), 1);
+    }
+}
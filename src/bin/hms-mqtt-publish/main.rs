@@ -4,25 +4,41 @@
 mod logging;
 mod rumqttc_wrapper;
 
-use clap::Parser;
+use chrono::{Datelike, Days, Local, Months, NaiveDate};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use core::panic;
 use hms2mqtt::sources::fake::FakeInverter;
 use hms2mqtt::sources::hms::inverter::HMSInverter;
 use hms2mqtt::sources::inverter::Inverter;
+use hms2mqtt::targets::file_logger::{FileLogger, FileLoggerConfig};
+use hms2mqtt::targets::graphite::{Graphite, GraphiteConfig};
+use hms2mqtt::targets::influxdb::{InfluxDb, InfluxDbConfig};
 use hms2mqtt::targets::metric_publisher::MetricPublisher;
 use hms2mqtt::targets::mqtt::home_assistant::HomeAssistant;
 use hms2mqtt::targets::mqtt::mqtt::Mqtt;
 use hms2mqtt::targets::mqtt::mqtt_config::MqttConfig;
+use hms2mqtt::targets::mqtt::retained_topics::purge_state_file;
 use hms2mqtt::targets::mqtt::simple_mqtt::SimpleMqtt;
+use hms2mqtt::targets::nats::{Nats, NatsConfig};
+use hms2mqtt::targets::offline_buffer::{OfflineBuffer, OfflineBufferConfig};
+use hms2mqtt::targets::otlp::{Otlp, OtlpConfig};
+use hms2mqtt::targets::postgres::{Postgres, PostgresConfig};
+use hms2mqtt::targets::prometheus::{Prometheus, PrometheusConfig};
+use hms2mqtt::targets::pvoutput::{PvOutput, PvOutputConfig};
+use hms2mqtt::targets::redis::{Redis, RedisConfig};
+use hms2mqtt::targets::sqlite::{query_history, HistoryKind, HistoryQuery, Sqlite, SqliteConfig};
+use hms2mqtt::targets::statsd::{Statsd, StatsdConfig};
+use hms2mqtt::targets::webhook::{Webhook, WebhookConfig};
 use rumqttc_wrapper::RumqttcWrapper;
 use serde_derive::Deserialize;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
 use std::path::PathBuf;
 use std::thread;
 use std::time::Duration;
 
-use log::info;
+use log::{error, info};
 
 // TODO: update once https://togithub.com/serde-rs/serde/issues/368 is closed
 fn default_update_interval() -> u64 {
@@ -37,6 +53,18 @@ struct Config {
     home_assistant: Option<MqttConfig>,
     simple_mqtt: Option<MqttConfig>,
     mqtt: Option<MqttConfig>,
+    prometheus: Option<PrometheusConfig>,
+    influxdb: Option<InfluxDbConfig>,
+    file_logger: Option<FileLoggerConfig>,
+    sqlite: Option<SqliteConfig>,
+    webhook: Option<WebhookConfig>,
+    otlp: Option<OtlpConfig>,
+    graphite: Option<GraphiteConfig>,
+    statsd: Option<StatsdConfig>,
+    postgres: Option<PostgresConfig>,
+    nats: Option<NatsConfig>,
+    redis: Option<RedisConfig>,
+    pvoutput: Option<PvOutputConfig>,
 }
 
 #[derive(Parser)]
@@ -49,6 +77,59 @@ struct Cli {
     /// Path to the configuration file
     #[arg(short, long, default_value = "config.toml")]
     config: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Remove all retained topics the configured targets remember publishing
+    Purge(PurgeArgs),
+    /// Show the history stored by the SQLite target
+    History(HistoryArgs),
+}
+
+#[derive(Args)]
+struct PurgeArgs {
+    /// State file of a target removed from the config, purged via the broker of the first
+    /// configured MQTT target
+    #[arg(long = "state-file")]
+    state_files: Vec<PathBuf>,
+}
+
+#[derive(Args)]
+struct HistoryArgs {
+    #[arg(value_enum)]
+    kind: HistoryKindArg,
+    /// Serial or alias of the DTU, required if the history holds several DTUs
+    #[arg(long)]
+    dtu: Option<String>,
+    /// Show the history of a single port
+    #[arg(long)]
+    port: Option<i32>,
+    /// First day, defaults to 30 days, 12 months or today depending on the kind
+    #[arg(long)]
+    from: Option<NaiveDate>,
+    /// Last day, defaults to today
+    #[arg(long)]
+    to: Option<NaiveDate>,
+    /// Seconds per row of the power curve
+    #[arg(long, default_value_t = 900)]
+    interval: u32,
+    /// Print CSV instead of a table
+    #[arg(long)]
+    csv: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum HistoryKindArg {
+    /// Energy per day
+    Daily,
+    /// Energy per month
+    Monthly,
+    /// Power curve
+    Power,
 }
 
 fn load_config(path: &PathBuf) -> Result<Config, Box<dyn Error>> {
@@ -66,13 +147,153 @@ fn load_config(path: &PathBuf) -> Result<Config, Box<dyn Error>> {
     Ok(config)
 }
 
+fn purge(config: Config, args: PurgeArgs) {
+    let broker = [&config.home_assistant, &config.mqtt, &config.simple_mqtt]
+        .into_iter()
+        .flatten()
+        .next()
+        .cloned();
+
+    if let Some(config) = config.home_assistant {
+        info!("Purging Home Assistant topics");
+        if let Err(e) =
+            HomeAssistant::<RumqttcWrapper>::new(&config).and_then(|target| target.purge())
+        {
+            error!("Failed to purge Home Assistant topics: {e:?}");
+        }
+    }
+
+    if let Some(config) = config.mqtt {
+        info!("Purging MQTT topics");
+        if let Err(e) = Mqtt::<RumqttcWrapper>::new(&config).and_then(|target| target.purge()) {
+            error!("Failed to purge MQTT topics: {e:?}");
+        }
+    }
+
+    if args.state_files.is_empty() {
+        return;
+    }
+    let Some(broker) = broker else {
+        error!("Purging state files requires an MQTT target in the config");
+        return;
+    };
+    for state_file in args.state_files {
+        info!("Purging topics of {state_file:?}");
+        if let Err(e) = purge_state_file::<RumqttcWrapper>(&broker, &state_file) {
+            error!("Failed to purge topics of {state_file:?}: {e:?}");
+        }
+    }
+}
+
+fn history(config: Config, args: HistoryArgs) -> anyhow::Result<()> {
+    let Some(sqlite) = config.sqlite else {
+        anyhow::bail!("the history requires an [sqlite] section in the config");
+    };
+
+    let today = Local::now().date_naive();
+    let (kind, default_from) = match args.kind {
+        HistoryKindArg::Daily => (HistoryKind::Daily, today - Days::new(30)),
+        HistoryKindArg::Monthly => (
+            HistoryKind::Monthly,
+            (today - Months::new(11)).with_day(1).unwrap_or(today),
+        ),
+        HistoryKindArg::Power => (HistoryKind::Power, today),
+    };
+    let query = HistoryQuery {
+        kind,
+        dtu: args.dtu,
+        port: args.port,
+        from: args.from.unwrap_or(default_from),
+        to: args.to.unwrap_or(today),
+        interval: args.interval,
+    };
+    let history = query_history(&sqlite.file, &query)?;
+    if args.csv {
+        print!("{}", history.to_csv());
+    } else {
+        print!("{}", history.to_table());
+    }
+    Ok(())
+}
+
+fn exit_with_error(target: &str, e: anyhow::Error) -> ! {
+    error!("Failed to set up the {target} target: {e:?}");
+    std::process::exit(1);
+}
+
+/// Offline buffers rewrite their file, so a file shared by two targets loses snapshots
+fn check_offline_buffer_files(config: &Config) -> anyhow::Result<()> {
+    fn mqtt_buffer(config: &Option<MqttConfig>) -> Option<&OfflineBufferConfig> {
+        config
+            .as_ref()
+            .and_then(|config| config.offline_buffer.as_ref())
+    }
+    let buffers = [
+        ("home_assistant", mqtt_buffer(&config.home_assistant)),
+        ("mqtt", mqtt_buffer(&config.mqtt)),
+        ("simple_mqtt", mqtt_buffer(&config.simple_mqtt)),
+        (
+            "postgres",
+            config
+                .postgres
+                .as_ref()
+                .and_then(|config| config.offline_buffer.as_ref()),
+        ),
+    ];
+    let mut files = HashMap::new();
+    for (target, buffer) in buffers {
+        let Some(buffer) = buffer else {
+            continue;
+        };
+        if let Some(other) = files.insert(&buffer.file, target) {
+            anyhow::bail!(
+                "the offline buffers of {other} and {target} use the same file {:?}",
+                buffer.file
+            );
+        }
+    }
+    Ok(())
+}
+
+fn with_offline_buffer(
+    target: impl MetricPublisher + 'static,
+    offline_buffer: &Option<OfflineBufferConfig>,
+) -> Box<dyn MetricPublisher> {
+    match offline_buffer {
+        Some(buffer_config) => Box::new(OfflineBuffer::new(target, buffer_config.clone())),
+        None => Box::new(target),
+    }
+}
+
 fn main() {
     logging::init_logger();
     let args = Cli::parse();
     info!("Running revision: {}", env!("GIT_HASH"));
 
-    // TODO: proper error handling
-    let config: Config = load_config(&args.config).expect("Failed to load config");
+    let config: Config = load_config(&args.config).unwrap_or_else(|e| {
+        error!("Failed to load config {:?}: {e}", args.config);
+        std::process::exit(1);
+    });
+
+    match args.command {
+        Some(Command::Purge(purge_args)) => {
+            purge(config, purge_args);
+            return;
+        }
+        Some(Command::History(history_args)) => {
+            if let Err(e) = history(config, history_args) {
+                error!("Failed to show the history: {e:?}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+
+    if let Err(e) = check_offline_buffer_files(&config) {
+        error!("Invalid offline buffer config: {e}");
+        std::process::exit(1);
+    }
 
     info!("inverter hosts: {:?}", config.inverter_hosts);
     let mut inverters: Vec<Box<dyn Inverter>> = config
@@ -91,27 +312,113 @@ fn main() {
     let mut output_channels: Vec<Box<dyn MetricPublisher>> = Vec::new();
     if let Some(config) = config.home_assistant {
         info!("Publishing to Home Assistant");
-        output_channels.push(Box::new(HomeAssistant::<RumqttcWrapper>::new(&config)));
+        let target = HomeAssistant::<RumqttcWrapper>::new(&config)
+            .unwrap_or_else(|e| exit_with_error("Home Assistant", e));
+        output_channels.push(with_offline_buffer(target, &config.offline_buffer));
     }
 
     if let Some(config) = config.mqtt {
         info!("Publishing to MQTT broker");
-        output_channels.push(Box::new(Mqtt::<RumqttcWrapper>::new(&config)));
+        let target =
+            Mqtt::<RumqttcWrapper>::new(&config).unwrap_or_else(|e| exit_with_error("MQTT", e));
+        output_channels.push(with_offline_buffer(target, &config.offline_buffer));
     }
 
     if let Some(config) = config.simple_mqtt {
         info!("Publishing to simple MQTT broker");
-        output_channels.push(Box::new(SimpleMqtt::<RumqttcWrapper>::new(&config)));
+        let target = SimpleMqtt::<RumqttcWrapper>::new(&config)
+            .unwrap_or_else(|e| exit_with_error("simple MQTT", e));
+        output_channels.push(with_offline_buffer(target, &config.offline_buffer));
+    }
+
+    if let Some(config) = config.prometheus {
+        info!("Serving Prometheus metrics");
+        let target = Prometheus::new(&config).unwrap_or_else(|e| exit_with_error("Prometheus", e));
+        output_channels.push(Box::new(target));
+    }
+
+    if let Some(config) = config.influxdb {
+        info!("Writing to InfluxDB");
+        let target = InfluxDb::new(&config).unwrap_or_else(|e| exit_with_error("InfluxDB", e));
+        output_channels.push(Box::new(target));
+    }
+
+    if let Some(config) = config.file_logger {
+        info!("Logging to files in {:?}", config.directory);
+        let target = FileLogger::new(&config).unwrap_or_else(|e| exit_with_error("file logger", e));
+        output_channels.push(Box::new(target));
+    }
+
+    if let Some(config) = config.sqlite {
+        info!("Storing the history in {:?}", config.file);
+        let target = Sqlite::new(&config).unwrap_or_else(|e| exit_with_error("SQLite", e));
+        output_channels.push(Box::new(target));
+    }
+
+    if let Some(config) = config.webhook {
+        info!("Posting to webhooks {:?}", config.urls);
+        let target = Webhook::new(&config).unwrap_or_else(|e| exit_with_error("webhook", e));
+        output_channels.push(Box::new(target));
+    }
+
+    if let Some(config) = config.otlp {
+        info!("Exporting to OpenTelemetry collector {}", config.endpoint);
+        let target = Otlp::new(&config).unwrap_or_else(|e| exit_with_error("OpenTelemetry", e));
+        output_channels.push(Box::new(target));
+    }
+
+    if let Some(config) = config.graphite {
+        info!("Writing to Graphite at {}:{}", config.host, config.port);
+        let target = Graphite::new(&config).unwrap_or_else(|e| exit_with_error("Graphite", e));
+        output_channels.push(Box::new(target));
+    }
+
+    if let Some(config) = config.statsd {
+        info!("Sending to StatsD at {}:{}", config.host, config.port);
+        let target = Statsd::new(&config).unwrap_or_else(|e| exit_with_error("StatsD", e));
+        output_channels.push(Box::new(target));
+    }
+
+    if let Some(config) = config.postgres {
+        info!("Writing to PostgreSQL table {}", config.table);
+        let target = Postgres::new(&config).unwrap_or_else(|e| exit_with_error("PostgreSQL", e));
+        output_channels.push(with_offline_buffer(target, &config.offline_buffer));
+    }
+
+    if let Some(config) = config.nats {
+        info!("Publishing to NATS at {}:{}", config.host, config.port);
+        let target = Nats::new(&config).unwrap_or_else(|e| exit_with_error("NATS", e));
+        output_channels.push(Box::new(target));
+    }
+
+    if let Some(config) = config.redis {
+        info!("Adding to Redis streams at {}:{}", config.host, config.port);
+        let target = Redis::new(&config).unwrap_or_else(|e| exit_with_error("Redis", e));
+        output_channels.push(Box::new(target));
+    }
+
+    if let Some(config) = config.pvoutput {
+        info!(
+            "Uploading {}-minute statuses to PVOutput",
+            config.status_interval
+        );
+        let target = PvOutput::new(&config).unwrap_or_else(|e| exit_with_error("PVOutput", e));
+        output_channels.push(Box::new(target));
     }
 
     loop {
-        inverters.iter_mut().for_each(|inverter| {
-            if let Some(r) = inverter.update_state() {
+        inverters
+            .iter_mut()
+            .zip(&config.inverter_hosts)
+            .for_each(|(inverter, host)| {
+                let state = inverter.update_state();
                 output_channels.iter_mut().for_each(|channel| {
-                    channel.publish(&r);
+                    channel.poll_result(host, state.is_some());
+                    if let Some(r) = &state {
+                        channel.publish(r);
+                    }
                 })
-            }
-        });
+            });
 
         // TODO: the sleep has to move into the Inverter struct in an async implementation
         thread::sleep(Duration::from_millis(config.update_interval));
@@ -1,16 +1,103 @@
-use std::{sync::mpsc::Sender, thread, time::Duration};
+use std::{
+    fmt::Display,
+    future::{self, Ready},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
+use anyhow::{bail, Context};
 use hms2mqtt::targets::mqtt::{
-    mqtt_config::MqttConfig,
-    mqtt_wrapper::{self, PublishEvent},
+    mqtt_config::{MqttConfig, ProtocolVersion},
+    mqtt_wrapper::{self, ConnectionState, PublishEvent, PublishProperties},
 };
+use http::{HeaderMap, HeaderName, HeaderValue};
+use log::{debug, error, info, warn};
 use rumqttc::{
-    tokio_rustls::{self, rustls::ClientConfig},
-    Client, Event, Incoming, MqttOptions, Transport,
+    tokio_rustls::rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    },
+    v5, Client, Event, Incoming, MqttOptions, Outgoing, TlsConfiguration, Transport,
 };
 
+enum RumqttcClient {
+    V3(Client),
+    V5(v5::Client),
+}
+
 pub struct RumqttcWrapper {
-    client: Client,
+    client: RumqttcClient,
+    event_loop: Option<JoinHandle<()>>,
+    /// Number of accepted connections while connected, 0 while disconnected
+    connection: Arc<AtomicU64>,
+    /// Makes the event loop give up on connection errors instead of reconnecting
+    disconnecting: Arc<AtomicBool>,
+}
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Time to wait for pending messages when disconnecting
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection bookkeeping of the event loop thread
+struct EventLoopState {
+    connection: Arc<AtomicU64>,
+    connections: u64,
+    reconnect_delay: Duration,
+}
+
+impl EventLoopState {
+    fn new(connection: Arc<AtomicU64>) -> Self {
+        Self {
+            connection,
+            connections: 0,
+            reconnect_delay: MIN_RECONNECT_DELAY,
+        }
+    }
+
+    fn connected(&mut self) {
+        self.connections += 1;
+        self.connection.store(self.connections, Ordering::Relaxed);
+        self.reconnect_delay = MIN_RECONNECT_DELAY;
+        if self.connections > 1 {
+            info!("Reconnected to the MQTT broker");
+        } else {
+            info!("Connected to the MQTT broker");
+        }
+    }
+
+    /// Waits before the next connection attempt, doubling the delay after every failure
+    fn failed(&mut self, error: impl Display) {
+        if self.connection.swap(0, Ordering::Relaxed) > 0 {
+            warn!("MQTT connection lost: {error}");
+        } else {
+            warn!(
+                "MQTT connection failed: {error}, retrying in {}s",
+                self.reconnect_delay.as_secs()
+            );
+        }
+        thread::sleep(self.reconnect_delay);
+        self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+impl Drop for EventLoopState {
+    // also runs when the event loop panics, so the targets notice the missing connection
+    fn drop(&mut self) {
+        self.connection.store(0, Ordering::Relaxed);
+        if thread::panicking() {
+            error!("MQTT event loop panicked, no more messages are sent");
+        } else {
+            debug!("MQTT event loop stopped");
+        }
+    }
 }
 
 // TODO: Is the a better way to implement Into or From for external stuff?
@@ -34,9 +121,230 @@ fn match_qos(qos: mqtt_wrapper::QoS) -> rumqttc::QoS {
     }
 }
 
+fn match_v5_qos(qos: mqtt_wrapper::QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        mqtt_wrapper::QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        mqtt_wrapper::QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        mqtt_wrapper::QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+fn from_v5_qos(qos: v5::mqttbytes::QoS) -> mqtt_wrapper::QoS {
+    match qos {
+        v5::mqttbytes::QoS::AtMostOnce => mqtt_wrapper::QoS::AtMostOnce,
+        v5::mqttbytes::QoS::AtLeastOnce => mqtt_wrapper::QoS::AtLeastOnce,
+        v5::mqttbytes::QoS::ExactlyOnce => mqtt_wrapper::QoS::ExactlyOnce,
+    }
+}
+
+fn match_properties(properties: PublishProperties) -> v5::mqttbytes::v5::PublishProperties {
+    v5::mqttbytes::v5::PublishProperties {
+        content_type: properties.content_type,
+        message_expiry_interval: properties.message_expiry,
+        user_properties: properties.user_properties,
+        ..Default::default()
+    }
+}
+
+/// Accepts every broker certificate, used with the `insecure` option
+#[derive(Debug)]
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn load_root_certificates(config: &MqttConfig) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match &config.ca_file {
+        Some(ca_file) => {
+            for cert in CertificateDer::pem_file_iter(ca_file)
+                .with_context(|| format!("could not read CA file {ca_file:?}"))?
+            {
+                let cert = cert.with_context(|| format!("invalid certificate in {ca_file:?}"))?;
+                roots
+                    .add(cert)
+                    .with_context(|| format!("invalid CA certificate in {ca_file:?}"))?;
+            }
+            if roots.is_empty() {
+                bail!("no certificates found in CA file {ca_file:?}");
+            }
+        }
+        None => {
+            // Use rustls-native-certs to load root certificates from the operating system.
+            let native_certs = rustls_native_certs::load_native_certs();
+            for e in native_certs.errors {
+                warn!("could not load platform certificate: {e}");
+            }
+            let (_, ignored) = roots.add_parsable_certificates(native_certs.certs);
+            if ignored > 0 {
+                warn!("ignored {ignored} invalid platform certificates");
+            }
+            if roots.is_empty() {
+                bail!("no platform certificates found, configure a ca_file");
+            }
+        }
+    }
+    Ok(roots)
+}
+
+fn tls_configuration(config: &MqttConfig) -> anyhow::Result<TlsConfiguration> {
+    let builder = if config.insecure {
+        warn!("TLS certificate verification is disabled");
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+    } else {
+        ClientConfig::builder().with_root_certificates(load_root_certificates(config)?)
+    };
+
+    let mut client_config = match (&config.client_cert, &config.client_key) {
+        (Some(cert_file), Some(key_file)) => {
+            let certs = CertificateDer::pem_file_iter(cert_file)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .with_context(|| format!("could not read client certificate {cert_file:?}"))?;
+            let key = PrivateKeyDer::from_pem_file(key_file)
+                .with_context(|| format!("could not read client key {key_file:?}"))?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .context("invalid client certificate or key")?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => bail!("client_cert and client_key have to be configured together"),
+    };
+    client_config.alpn_protocols = config
+        .alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+
+    Ok(client_config.into())
+}
+
+/// Address, port and transport of the broker for both client versions
+struct Endpoint {
+    broker_addr: String,
+    port: u16,
+    transport: Transport,
+    /// Additional headers of the websocket upgrade request
+    headers: HeaderMap,
+}
+
+fn get_endpoint(config: &MqttConfig) -> anyhow::Result<Endpoint> {
+    let use_tls = config.tls.is_some_and(|tls| tls);
+    let tls_options = config.ca_file.is_some()
+        || config.client_cert.is_some()
+        || config.client_key.is_some()
+        || !config.alpn.is_empty()
+        || config.insecure;
+    if tls_options && !use_tls {
+        bail!("ca_file, client_cert, client_key, alpn and insecure require tls = true");
+    }
+    let port = config
+        .port
+        .unwrap_or(match (config.websocket.is_some(), use_tls) {
+            (false, false) => 1883,
+            (false, true) => 8883,
+            (true, false) => 80,
+            (true, true) => 443,
+        });
+
+    let Some(websocket) = &config.websocket else {
+        let transport = if use_tls {
+            Transport::tls_with_config(tls_configuration(config)?)
+        } else {
+            Transport::Tcp
+        };
+        return Ok(Endpoint {
+            broker_addr: config.host.clone(),
+            port,
+            transport,
+            headers: HeaderMap::new(),
+        });
+    };
+
+    let (scheme, transport) = if use_tls {
+        (
+            "wss",
+            Transport::wss_with_config(tls_configuration(config)?),
+        )
+    } else {
+        ("ws", Transport::Ws)
+    };
+    let host = if config.host.contains(':') {
+        format!("[{}]", config.host)
+    } else {
+        config.host.clone()
+    };
+    let path = websocket.path.trim_start_matches('/');
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in &websocket.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("invalid websocket header name '{name}'"))?;
+        let value = HeaderValue::from_str(value)
+            .with_context(|| format!("invalid value of websocket header '{name}'"))?;
+        headers.insert(name, value);
+    }
+
+    Ok(Endpoint {
+        // rumqttc takes host and port of websockets from the URL
+        broker_addr: format!("{scheme}://{host}:{port}/{path}"),
+        port,
+        transport,
+        headers,
+    })
+}
+
+fn add_headers(
+    headers: HeaderMap,
+) -> impl Fn(http::Request<()>) -> Ready<http::Request<()>> + Send + Sync + 'static {
+    move |mut request| {
+        request.headers_mut().extend(headers.clone());
+        future::ready(request)
+    }
+}
+
 impl mqtt_wrapper::MqttWrapper for RumqttcWrapper {
     fn subscribe(&mut self, topic: &str, qos: mqtt_wrapper::QoS) -> anyhow::Result<()> {
-        Ok(self.client.subscribe(topic, match_qos(qos))?)
+        match &self.client {
+            RumqttcClient::V3(client) => client.subscribe(topic, match_qos(qos))?,
+            RumqttcClient::V5(client) => client.subscribe(topic, match_v5_qos(qos))?,
+        }
+        Ok(())
     }
 
     fn publish<S, V>(
@@ -50,50 +358,101 @@ impl mqtt_wrapper::MqttWrapper for RumqttcWrapper {
         S: Clone + Into<String>,
         V: Clone + Into<Vec<u8>>,
     {
-        self.client
-            .publish(topic, match_qos(qos), retain, payload.clone())
-            .unwrap();
+        self.publish_with_properties(topic, qos, retain, payload, PublishProperties::default())
+    }
+
+    fn publish_with_properties<S, V>(
+        &mut self,
+        topic: S,
+        qos: mqtt_wrapper::QoS,
+        retain: bool,
+        payload: V,
+        properties: PublishProperties,
+    ) -> anyhow::Result<()>
+    where
+        S: Clone + Into<String>,
+        V: Clone + Into<Vec<u8>>,
+    {
+        // the request channel is only drained while connected, a full channel must not block
+        // the poll loop for the whole reconnect delay
+        if self.connection_state() == ConnectionState::Disconnected {
+            bail!("not connected to the MQTT broker");
+        }
+        match &self.client {
+            RumqttcClient::V3(client) => {
+                client.try_publish(topic, match_qos(qos), retain, payload.clone())?
+            }
+            RumqttcClient::V5(client) => client.try_publish_with_properties(
+                topic,
+                match_v5_qos(qos),
+                retain,
+                payload.into(),
+                match_properties(properties),
+            )?,
+        }
         Ok(())
     }
 
-    fn new(config: &MqttConfig, pub_tx: Sender<PublishEvent>) -> Self {
-        let use_tls = config.tls.is_some_and(|tls| tls);
+    fn connection_state(&self) -> ConnectionState {
+        match self.connection.load(Ordering::Relaxed) {
+            0 => ConnectionState::Disconnected,
+            connections => ConnectionState::Connected(connections),
+        }
+    }
 
-        let mut mqttoptions = MqttOptions::new(
-            &config.client_id,
-            &config.host,
-            config.port.unwrap_or_else(|| {
-                if use_tls {
-                    return 8883;
-                }
-                1883
-            }),
-        );
+    fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.disconnecting.store(true, Ordering::Relaxed);
+        // a full request channel must not block, the event loop may be stuck reconnecting
+        match &self.client {
+            RumqttcClient::V3(client) => client.try_disconnect()?,
+            RumqttcClient::V5(client) => client.try_disconnect()?,
+        }
+        // the event loop terminates once the disconnect has been sent, after all pending messages
+        let Some(event_loop) = self.event_loop.take() else {
+            return Ok(());
+        };
+        let deadline = Instant::now() + DISCONNECT_TIMEOUT;
+        while !event_loop.is_finished() {
+            if Instant::now() >= deadline {
+                bail!("timed out sending the pending MQTT messages");
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        if event_loop.join().is_err() {
+            bail!("MQTT event loop panicked");
+        }
+        Ok(())
+    }
+
+    fn new(config: &MqttConfig, pub_tx: Sender<PublishEvent>) -> anyhow::Result<Self> {
+        match config.protocol_version {
+            ProtocolVersion::V3 => Self::new_v3(config, pub_tx),
+            ProtocolVersion::V5 => Self::new_v5(config, pub_tx),
+        }
+    }
+}
+
+//parse the mqtt authentication options
+fn get_credentials(config: &MqttConfig) -> Option<(String, String)> {
+    match (&config.username, &config.password) {
+        (None, None) => None,
+        (None, Some(_)) => None,
+        (Some(username), None) => Some((username.clone(), "".into())),
+        (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+    }
+}
+
+impl RumqttcWrapper {
+    fn new_v3(config: &MqttConfig, pub_tx: Sender<PublishEvent>) -> anyhow::Result<Self> {
+        let endpoint = get_endpoint(config)?;
+        let mut mqttoptions =
+            MqttOptions::new(&config.client_id, &endpoint.broker_addr, endpoint.port);
         mqttoptions.set_keep_alive(Duration::from_secs(5));
-        if use_tls {
-            // Use rustls-native-certs to load root certificates from the operating system.
-            let mut roots = tokio_rustls::rustls::RootCertStore::empty();
-            rustls_native_certs::load_native_certs()
-                .expect("could not load platform certs")
-                .into_iter()
-                .for_each(|cert| {
-                    roots.add(cert).unwrap();
-                });
-
-            let client_config = ClientConfig::builder()
-                .with_root_certificates(roots)
-                .with_no_client_auth();
-
-            mqttoptions.set_transport(Transport::tls_with_config(client_config.into()));
-        }
-
-        //parse the mqtt authentication options
-        if let Some((username, password)) = match (&config.username, &config.password) {
-            (None, None) => None,
-            (None, Some(_)) => None,
-            (Some(username), None) => Some((username.clone(), "".into())),
-            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
-        } {
+        mqttoptions.set_transport(endpoint.transport);
+        if !endpoint.headers.is_empty() {
+            mqttoptions.set_request_modifier(add_headers(endpoint.headers));
+        }
+        if let Some((username, password)) = get_credentials(config) {
             mqttoptions.set_credentials(username, password);
         }
         let status_topic = format!("{}/status", config.base_topic);
@@ -101,32 +460,375 @@ impl mqtt_wrapper::MqttWrapper for RumqttcWrapper {
         mqttoptions.set_last_will(rumqttc::LastWill::new(
             &status_topic,
             "offline",
-            rumqttc::QoS::ExactlyOnce,
+            match_qos(config.status_qos),
             true,
         ));
 
         let (client, mut connection) = Client::new(mqttoptions, 512);
 
-        // Birth message
-        let _ = client.publish(&status_topic, rumqttc::QoS::ExactlyOnce, true, "online");
-
-        thread::spawn(move || {
+        let connection_state = Arc::new(AtomicU64::new(0));
+        let mut state = EventLoopState::new(connection_state.clone());
+        let disconnecting = Arc::new(AtomicBool::new(false));
+        let event_loop_disconnecting = disconnecting.clone();
+        let birth_client = client.clone();
+        let qos = match_qos(config.status_qos);
+        let event_loop = thread::spawn(move || {
+            let disconnecting = event_loop_disconnecting;
             // keep polling the event loop to make sure outgoing messages get sent
             // the call to .iter() blocks and suspends the thread effectively by
             // calling .recv() under the hood. This implies that the loop terminates
             // once the client unsubs
-            for event in connection.iter().flatten() {
-                if let Event::Incoming(Incoming::Publish(packet)) = event {
-                    let pub_event = PublishEvent {
-                        topic: packet.topic,
-                        qos: RumqttcQosWrapper(packet.qos).into(),
-                        retain: packet.retain,
-                        payload: packet.payload,
-                    };
-                    pub_tx.send(pub_event).unwrap();
+            for notification in connection.iter() {
+                let event = match notification {
+                    Ok(event) => event,
+                    // pending messages can't be sent anymore, don't keep reconnecting
+                    Err(e) if disconnecting.load(Ordering::Relaxed) => {
+                        warn!("Dropping pending MQTT messages: {e}");
+                        break;
+                    }
+                    Err(e) => {
+                        state.failed(e);
+                        continue;
+                    }
+                };
+                match event {
+                    Event::Incoming(Incoming::ConnAck(_)) => {
+                        state.connected();
+                        // Birth message, replaces the last will after every reconnect. Blocking
+                        // here could deadlock with a full request channel.
+                        if let Err(e) = birth_client.try_publish(&status_topic, qos, true, "online")
+                        {
+                            warn!("Failed to publish birth message: {e}");
+                        }
+                    }
+                    Event::Incoming(Incoming::Publish(packet)) => {
+                        let pub_event = PublishEvent {
+                            topic: packet.topic,
+                            qos: RumqttcQosWrapper(packet.qos).into(),
+                            retain: packet.retain,
+                            payload: packet.payload,
+                        };
+                        // targets that do not subscribe drop the receiver
+                        let _ = pub_tx.send(pub_event);
+                    }
+                    Event::Outgoing(Outgoing::Disconnect) => break,
+                    _ => {}
                 }
             }
         });
-        Self { client }
+        Ok(Self {
+            client: RumqttcClient::V3(client),
+            event_loop: Some(event_loop),
+            connection: connection_state,
+            disconnecting,
+        })
+    }
+
+    fn new_v5(config: &MqttConfig, pub_tx: Sender<PublishEvent>) -> anyhow::Result<Self> {
+        let endpoint = get_endpoint(config)?;
+        let mut mqttoptions =
+            v5::MqttOptions::new(&config.client_id, &endpoint.broker_addr, endpoint.port);
+        mqttoptions.set_keep_alive(Duration::from_secs(5));
+        mqttoptions.set_transport(endpoint.transport);
+        if !endpoint.headers.is_empty() {
+            mqttoptions.set_request_modifier(add_headers(endpoint.headers));
+        }
+        if let Some((username, password)) = get_credentials(config) {
+            mqttoptions.set_credentials(username, password);
+        }
+        let status_topic = format!("{}/status", config.base_topic);
+
+        mqttoptions.set_last_will(v5::mqttbytes::v5::LastWill::new(
+            &status_topic,
+            "offline",
+            match_v5_qos(config.status_qos),
+            true,
+            None,
+        ));
+
+        let (client, mut connection) = v5::Client::new(mqttoptions, 512);
+
+        let connection_state = Arc::new(AtomicU64::new(0));
+        let mut state = EventLoopState::new(connection_state.clone());
+        let disconnecting = Arc::new(AtomicBool::new(false));
+        let event_loop_disconnecting = disconnecting.clone();
+        let birth_client = client.clone();
+        let qos = match_v5_qos(config.status_qos);
+        let event_loop = thread::spawn(move || {
+            let disconnecting = event_loop_disconnecting;
+            for notification in connection.iter() {
+                let event = match notification {
+                    Ok(event) => event,
+                    Err(e) if disconnecting.load(Ordering::Relaxed) => {
+                        warn!("Dropping pending MQTT messages: {e}");
+                        break;
+                    }
+                    Err(e) => {
+                        // refused connections carry the reason code of the broker
+                        state.failed(e);
+                        continue;
+                    }
+                };
+                match event {
+                    v5::Event::Incoming(v5::Incoming::Publish(packet)) => {
+                        let pub_event = PublishEvent {
+                            topic: String::from_utf8_lossy(&packet.topic).into_owned(),
+                            qos: from_v5_qos(packet.qos),
+                            retain: packet.retain,
+                            payload: packet.payload,
+                        };
+                        // targets that do not subscribe drop the receiver
+                        let _ = pub_tx.send(pub_event);
+                    }
+                    v5::Event::Incoming(v5::Incoming::ConnAck(ack)) => {
+                        debug!("MQTT connection accepted: {:?}", ack.code);
+                        state.connected();
+                        if let Err(e) = birth_client.try_publish(&status_topic, qos, true, "online")
+                        {
+                            warn!("Failed to publish birth message: {e}");
+                        }
+                    }
+                    v5::Event::Incoming(v5::Incoming::PubAck(ack)) => {
+                        use v5::mqttbytes::v5::PubAckReason;
+                        match ack.reason {
+                            PubAckReason::Success | PubAckReason::NoMatchingSubscribers => {}
+                            reason => warn!(
+                                "MQTT broker rejected message {}: {reason:?} {}",
+                                ack.pkid,
+                                ack.properties
+                                    .and_then(|properties| properties.reason_string)
+                                    .unwrap_or_default()
+                            ),
+                        }
+                    }
+                    v5::Event::Incoming(v5::Incoming::PubRec(rec)) => {
+                        use v5::mqttbytes::v5::PubRecReason;
+                        match rec.reason {
+                            PubRecReason::Success | PubRecReason::NoMatchingSubscribers => {}
+                            reason => warn!(
+                                "MQTT broker rejected message {}: {reason:?} {}",
+                                rec.pkid,
+                                rec.properties
+                                    .and_then(|properties| properties.reason_string)
+                                    .unwrap_or_default()
+                            ),
+                        }
+                    }
+                    v5::Event::Incoming(v5::Incoming::Disconnect(disconnect)) => {
+                        warn!(
+                            "MQTT broker closed the connection: {:?} {}",
+                            disconnect.reason_code,
+                            disconnect
+                                .properties
+                                .and_then(|properties| properties.reason_string)
+                                .unwrap_or_default()
+                        );
+                    }
+                    v5::Event::Outgoing(Outgoing::Disconnect) => break,
+                    _ => {}
+                }
+            }
+        });
+        Ok(Self {
+            client: RumqttcClient::V5(client),
+            event_loop: Some(event_loop),
+            connection: connection_state,
+            disconnecting,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        net::TcpListener,
+        sync::{atomic::Ordering, mpsc::channel},
+        time::{Duration, Instant},
+    };
+
+    use hms2mqtt::targets::mqtt::{
+        mqtt_config::MqttConfig,
+        mqtt_wrapper::{MqttWrapper, QoS},
+    };
+
+    use rumqttc::Transport;
+
+    use super::{get_endpoint, tls_configuration, RumqttcWrapper, DISCONNECT_TIMEOUT};
+
+    fn config(extra: &str) -> MqttConfig {
+        toml::from_str(&format!("host = \"127.0.0.1\"\n{extra}")).unwrap()
+    }
+
+    fn error<T>(result: anyhow::Result<T>) -> String {
+        format!("{:#}", result.err().expect("expected an error"))
+    }
+
+    #[test]
+    fn test_disconnect_from_unreachable_broker() {
+        // a port nobody listens on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (tx, _rx) = channel();
+        let mut client = RumqttcWrapper::new(&config(&format!("port = {port}")), tx).unwrap();
+        // nothing is queued while disconnected, the offline buffer keeps the snapshot instead
+        assert!(client
+            .publish("hms/test", QoS::AtLeastOnce, true, "")
+            .is_err());
+        let start = Instant::now();
+        client.disconnect().unwrap();
+        assert!(start.elapsed() < DISCONNECT_TIMEOUT);
+    }
+
+    #[test]
+    fn test_publish_does_not_block_on_full_channel() {
+        // accepts the connection but never answers, so the event loop stays stuck connecting
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, _rx) = channel();
+        let mut client = RumqttcWrapper::new(&config(&format!("port = {port}")), tx).unwrap();
+        assert!(client
+            .publish("hms/test", QoS::AtMostOnce, false, "")
+            .is_err());
+
+        // a connection that got lost without the event loop noticing yet
+        client.connection.store(1, Ordering::Relaxed);
+        let start = Instant::now();
+        let failed = (0..1000)
+            .filter(|_| {
+                client
+                    .publish("hms/test", QoS::AtLeastOnce, false, "value")
+                    .is_err()
+            })
+            .count();
+        assert!(failed > 0);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(listener);
+    }
+
+    #[test]
+    fn test_tls_options_require_tls() {
+        for option in [
+            "ca_file = \"ca.crt\"",
+            "client_cert = \"client.crt\"",
+            "alpn = [\"mqtt\"]",
+            "insecure = true",
+        ] {
+            let message = error(get_endpoint(&config(option)));
+            assert!(message.contains("require tls = true"), "{message}");
+            let message = error(get_endpoint(&config(&format!("tls = false\n{option}"))));
+            assert!(message.contains("require tls = true"), "{message}");
+        }
+    }
+
+    #[test]
+    fn test_invalid_tls_files() {
+        let directory = std::env::temp_dir().join(format!("rumqttc_tls_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let invalid_pem = directory.join("invalid.pem");
+        fs::write(
+            &invalid_pem,
+            "-----BEGIN CERTIFICATE-----\nno base64!\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        let missing = directory.join("missing.pem");
+
+        let message = error(tls_configuration(&config(&format!(
+            "tls = true\nca_file = {missing:?}"
+        ))));
+        assert!(message.contains("could not read CA file"), "{message}");
+
+        let message = error(tls_configuration(&config(&format!(
+            "tls = true\nca_file = {invalid_pem:?}"
+        ))));
+        assert!(message.contains("invalid certificate"), "{message}");
+
+        let message = error(tls_configuration(&config(&format!(
+            "tls = true\ninsecure = true\nclient_cert = {invalid_pem:?}"
+        ))));
+        assert!(
+            message.contains("have to be configured together"),
+            "{message}"
+        );
+
+        let message = error(tls_configuration(&config(&format!(
+            "tls = true\ninsecure = true\nclient_cert = {invalid_pem:?}\nclient_key = {missing:?}"
+        ))));
+        assert!(
+            message.contains("could not read client certificate"),
+            "{message}"
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_endpoint() {
+        let endpoint = get_endpoint(&config("")).unwrap();
+        assert_eq!(endpoint.broker_addr, "127.0.0.1");
+        assert_eq!(endpoint.port, 1883);
+        assert!(matches!(endpoint.transport, Transport::Tcp));
+
+        // insecure avoids loading the platform certificates
+        let endpoint = get_endpoint(&config("tls = true\ninsecure = true")).unwrap();
+        assert_eq!(endpoint.port, 8883);
+        assert!(matches!(endpoint.transport, Transport::Tls(_)));
+
+        let endpoint = get_endpoint(&config("port = 1884")).unwrap();
+        assert_eq!(endpoint.port, 1884);
+    }
+
+    #[test]
+    fn test_websocket_endpoint() {
+        let endpoint = get_endpoint(&config("[websocket]")).unwrap();
+        assert_eq!(endpoint.broker_addr, "ws://127.0.0.1:80/mqtt");
+        assert_eq!(endpoint.port, 80);
+        assert!(matches!(endpoint.transport, Transport::Ws));
+        assert!(endpoint.headers.is_empty());
+
+        let endpoint = get_endpoint(&config(
+            "tls = true\ninsecure = true\n[websocket]\npath = \"ws\"",
+        ))
+        .unwrap();
+        assert_eq!(endpoint.broker_addr, "wss://127.0.0.1:443/ws");
+        assert_eq!(endpoint.port, 443);
+        assert!(matches!(endpoint.transport, Transport::Wss(_)));
+
+        let ipv6: MqttConfig =
+            toml::from_str("host = \"::1\"\nport = 8080\n[websocket]\npath = \"/proxy/mqtt\"")
+                .unwrap();
+        let endpoint = get_endpoint(&ipv6).unwrap();
+        assert_eq!(endpoint.broker_addr, "ws://[::1]:8080/proxy/mqtt");
+        assert_eq!(endpoint.port, 8080);
+    }
+
+    #[test]
+    fn test_websocket_headers() {
+        let endpoint = get_endpoint(&config(
+            "[websocket.headers]\nAuthorization = \"Bearer token\"\nX-Client = \"hms\"",
+        ))
+        .unwrap();
+        assert_eq!(endpoint.headers.len(), 2);
+        assert_eq!(endpoint.headers["authorization"], "Bearer token");
+        assert_eq!(endpoint.headers["x-client"], "hms");
+
+        let message = error(get_endpoint(&config(
+            "[websocket.headers]\n\"Invalid Name\" = \"value\"",
+        )));
+        assert!(
+            message.contains("invalid websocket header name"),
+            "{message}"
+        );
+
+        let message = error(get_endpoint(&config(
+            "[websocket.headers]\nX-Client = \"line\\nbreak\"",
+        )));
+        assert!(
+            message.contains("invalid value of websocket header"),
+            "{message}"
+        );
     }
 }
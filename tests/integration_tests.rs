@@ -40,10 +40,14 @@ impl MqttWrapper for MqttTester {
         Ok(())
     }
 
-    fn new(_config: &MqttConfig, _tx: Sender<PublishEvent>) -> Self {
-        Self {
+    fn disconnect(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn new(_config: &MqttConfig, _tx: Sender<PublishEvent>) -> anyhow::Result<Self> {
+        Ok(Self {
             published_values: Vec::new(),
-        }
+        })
     }
 }
 
@@ -62,7 +66,8 @@ fn publish_one_message() {
             ..Default::default()
         },
         tx,
-    );
+    )
+    .unwrap();
     let result = mqtt.publish("hms/foo", QoS::AtMostOnce, true, "Hooray".to_string());
     assert!(result.is_ok());
     assert!(!mqtt.is_empty());